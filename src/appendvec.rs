@@ -0,0 +1,175 @@
+use std::{
+    marker::PhantomData,
+    mem::MaybeUninit,
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+
+use crossbeam::epoch::Guard;
+
+// small blocks under Miri let tests reach block reuse with few elements
+const BLOCK_BITS: usize = if cfg!(miri) { 3 } else { 16 };
+const BLOCK_LEN: usize = 1 << BLOCK_BITS;
+
+// Append only vector that can be read without locks while it grows.
+// Elements are stored in fixed size blocks which are never moved once allocated,
+// the length is published only after the element is written.
+// Oldest elements can be evicted, their blocks are freed once no reader is pinned
+// and then reused as a ring, so indexes keep growing while capacity limits live elements.
+pub struct AppendVec<T> {
+    blocks: Box<[AtomicPtr<T>]>,
+    first: AtomicUsize,
+    len: AtomicUsize,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Copy> AppendVec<T> {
+    pub fn with_capacity(capacity: usize) -> Self {
+        AppendVec {
+            blocks: (0..capacity.div_ceil(BLOCK_LEN))
+                .map(|_| AtomicPtr::new(ptr::null_mut()))
+                .collect(),
+            first: AtomicUsize::new(0),
+            len: AtomicUsize::new(0),
+            _marker: PhantomData,
+        }
+    }
+
+    fn slot(&self, index: usize) -> &AtomicPtr<T> {
+        &self.blocks[(index >> BLOCK_BITS) % self.blocks.len()]
+    }
+
+    // index of the oldest element not evicted
+    pub fn first(&self) -> usize {
+        self.first.load(Ordering::Acquire)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    // Returns None if the element was evicted.
+    // The guard keeps blocks evicted while reading from being freed.
+    pub fn get(&self, index: usize, _guard: &Guard) -> Option<T> {
+        assert!(index < self.len(), "index out of bounds");
+        let block = self.slot(index).load(Ordering::Acquire);
+        // a block reused for newer elements is stored only after first moved past index
+        if block.is_null() || index < self.first() {
+            return None;
+        }
+        // SAFETY: elements below len are written and their block is not freed while pinned
+        Some(unsafe { *block.add(index & (BLOCK_LEN - 1)) })
+    }
+
+    // Appends the value and returns its index, panics when the capacity is exhausted.
+    //
+    // SAFETY: must not be called by more threads at once, also with evict
+    pub unsafe fn push(&self, value: T) -> usize {
+        let index = self.len.load(Ordering::Relaxed);
+        let slot = self.slot(index);
+
+        let mut block = slot.load(Ordering::Relaxed);
+        if index & (BLOCK_LEN - 1) == 0 {
+            assert!(block.is_null(), "capacity exhausted");
+            block = Box::into_raw(Box::<[T]>::new_uninit_slice(BLOCK_LEN)) as *mut T;
+            slot.store(block, Ordering::Release);
+        }
+        block.add(index & (BLOCK_LEN - 1)).write(value);
+
+        self.len.store(index + 1, Ordering::Release);
+        index
+    }
+
+    // Evicts elements below first, blocks holding only evicted elements are freed
+    // after all readers pinned at the moment are done.
+    //
+    // SAFETY: must not be called by more threads at once, also with push
+    pub unsafe fn evict(&self, first: usize, guard: &Guard) {
+        let old = self.first.load(Ordering::Relaxed);
+        if first <= old {
+            return;
+        }
+        assert!(first <= self.len(), "evicting past the end");
+        self.first.store(first, Ordering::Release);
+
+        for block in (old >> BLOCK_BITS)..(first >> BLOCK_BITS) {
+            let memory = self
+                .slot(block << BLOCK_BITS)
+                .swap(ptr::null_mut(), Ordering::AcqRel);
+            guard.defer_unchecked(move || free_block(memory));
+        }
+    }
+}
+
+// SAFETY: block must be allocated by push and not used anymore
+unsafe fn free_block<T>(block: *mut T) {
+    drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+        block as *mut MaybeUninit<T>,
+        BLOCK_LEN,
+    )));
+}
+
+impl<T> Drop for AppendVec<T> {
+    fn drop(&mut self) {
+        for slot in self.blocks.iter() {
+            let block = slot.load(Ordering::Relaxed);
+            if !block.is_null() {
+                // SAFETY: allocated by push, evicted blocks are not in a slot anymore
+                unsafe { free_block(block) };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    use crossbeam::epoch;
+
+    use super::*;
+
+    // Readers see every element below len either as written or as evicted, while one writer
+    // appends and evicts through a ring of a few blocks.
+    #[test]
+    fn concurrent_push_get_evict() {
+        let pushes = if cfg!(miri) { 200 } else { 1 << 20 };
+        let kept = 3 * BLOCK_LEN / 2;
+        let vec = AppendVec::<usize>::with_capacity(4 * BLOCK_LEN);
+        let done = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| {
+                    let mut last_first = 0;
+                    while !done.load(Ordering::Relaxed) {
+                        let guard = epoch::pin();
+                        let first = vec.first();
+                        let len = vec.len();
+                        assert!(first >= last_first && first <= len);
+                        last_first = first;
+                        for index in first.saturating_sub(4)..len {
+                            match vec.get(index, &guard) {
+                                Some(value) => assert_eq!(value, index),
+                                None => assert!(index < vec.first()),
+                            }
+                        }
+                    }
+                });
+            }
+
+            for value in 0..pushes {
+                // SAFETY: this is the only thread pushing and evicting
+                unsafe {
+                    assert_eq!(vec.push(value), value);
+                    vec.evict((value + 1).saturating_sub(kept), &epoch::pin());
+                }
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+
+        assert_eq!(vec.len(), pushes);
+        assert_eq!(vec.first(), pushes - kept);
+    }
+}
@@ -1,15 +1,118 @@
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{self, Ordering};
+use std::time::{Duration, Instant};
 
-use crossterm::event::{KeyCode, KeyEvent};
-use regex::Regex;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
 
-use crate::types::{Panel, SharedState, UIState};
+use crate::query::Query;
+use crate::types::{Filter, Panel, SharedState, UIState, WorkItem};
+use crate::view::{LogView, Row};
 
-fn update_search(app: &SharedState, re: Option<Regex>) {
+// typing restarts the search only after a pause
+const SEARCH_DEBOUNCE: Duration = Duration::from_millis(150);
+// columns scrolled by h and l
+const SCROLL_COLUMNS: isize = 3;
+// lines of the log scrolled by d and u
+const SCROLL_LINES: isize = 5;
+// lines of a search request, workers split it into chunks
+const SEARCH_LINES: usize = 1 << 16;
+
+// Keys typed so far of a motion, a count followed by the motion keys like 10j or gg
+#[derive(Default)]
+pub struct KeySequence {
+    count: Option<usize>,
+    // first key of a two key motion
+    prefix: Option<char>,
+}
+
+// Movement in a panel, counts are already applied
+enum Motion {
+    Lines(isize),
+    HalfPages(isize),
+    Pages(isize),
+    // line of the log, usize::MAX is the last one
+    GoTo(usize),
+    LogLines(isize),
+    Columns(isize),
+    Words(isize),
+    LineStart,
+    LineEnd,
+    // matches after or before the log position
+    Matches(isize),
+}
+
+enum Step {
+    // more keys are needed
+    Pending,
+    Motion(Motion),
+    // not a motion, the sequence is dropped
+    Other,
+}
+
+impl KeySequence {
+    fn feed(&mut self, key: KeyEvent) -> Step {
+        let KeyCode::Char(c) = key.code else {
+            *self = KeySequence::default();
+            return Step::Other;
+        };
+        let prefix = self.prefix.take();
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        match (prefix, c) {
+            // 0 is a motion unless it continues a count
+            (None, '0'..='9') if !control && (c != '0' || self.count.is_some()) => {
+                let digit = c.to_digit(10).unwrap() as usize;
+                let count = self.count.unwrap_or(0);
+                self.count = Some(count.saturating_mul(10).saturating_add(digit));
+                return Step::Pending;
+            }
+            (None, 'g') if !control => {
+                self.prefix = Some('g');
+                return Step::Pending;
+            }
+            _ => {}
+        }
+
+        let count = self.count.take();
+        let times = count.unwrap_or(1) as isize;
+        let motion = match (prefix, control, c) {
+            // gg goes to the first line or to the line of the count
+            (Some('g'), false, 'g') => Motion::GoTo(count.unwrap_or(1).saturating_sub(1)),
+            (Some(_), _, _) => return Step::Other,
+            (_, true, 'd') => Motion::HalfPages(times),
+            (_, true, 'u') => Motion::HalfPages(-times),
+            (_, true, 'f') => Motion::Pages(times),
+            (_, true, 'b') => Motion::Pages(-times),
+            (_, true, _) => return Step::Other,
+            (_, _, 'j') => Motion::Lines(times),
+            (_, _, 'k') => Motion::Lines(-times),
+            (_, _, 'd') => Motion::LogLines(SCROLL_LINES * times),
+            (_, _, 'u') => Motion::LogLines(-SCROLL_LINES * times),
+            (_, _, 'l') => Motion::Columns(SCROLL_COLUMNS * times),
+            (_, _, 'h') => Motion::Columns(-SCROLL_COLUMNS * times),
+            (_, _, 'w') => Motion::Words(times),
+            (_, _, 'b') => Motion::Words(-times),
+            (_, _, '0') => Motion::LineStart,
+            (_, _, '$') => Motion::LineEnd,
+            (_, _, 'n') => Motion::Matches(times),
+            (_, _, 'N') => Motion::Matches(-times),
+            // G goes to the last line or to the line of the count
+            (_, _, 'G') => Motion::GoTo(count.map_or(usize::MAX, |line| line.saturating_sub(1))),
+            _ => return Step::Other,
+        };
+        Step::Motion(motion)
+    }
+}
+
+// Returns version of the new search
+fn update_search(app: &SharedState, ui: &UIState, query: Option<Query>) -> usize {
     let mut search = app.search.write().unwrap();
-    search.re = re;
-    // TODO: do I need to hold the lock here?
-    app.search_version.fetch_add(1, Ordering::Relaxed);
+    search.query = query;
+    search.filters = ui.filters.iter().filter(|f| f.enabled).cloned().collect();
+    search.hidden_sources = ui.hidden_sources;
+    // bumped with the lock held so workers read the search and its version together
+    let version = app.search_version.fetch_add(1, Ordering::SeqCst) + 1;
+    let len = app.logbuf.read().len();
+    app.coverage.lock().unwrap().reset(version, len);
+    version
 }
 
 fn recompile_regex(app: &SharedState, ui: &mut UIState) {
@@ -20,24 +123,127 @@ fn recompile_regex(app: &SharedState, ui: &mut UIState) {
     ui.matches_selected = None;
     ui.matches_offset.y = 0;
 
-    if ui.search_query.len() < 3 {
-        update_search(app, None);
+    ui.search_due = None;
+    ui.search_error = None;
+    let query = Query::parse(&ui.search_query, ui.search_modes).unwrap_or_else(|error| {
+        ui.search_error = Some(error);
+        None
+    });
+    let version = update_search(app, ui, query);
+    ui.search_cancelled = false;
+    ui.search_started = None;
+    // filters alone are searched too
+    if !app.search.read().unwrap().is_active() {
         return;
     }
+    ui.search_started = Some(Instant::now());
+
+    enqueue_search(app, ui, version);
+}
+
+// Lines shown in the log panel are searched first, then the search spirals outward
+// taking a range below and a range above the searched region in turns.
+// Line lengths are not looked at here, that would take long for a large log.
+fn enqueue_search(app: &SharedState, ui: &UIState, version: usize) {
+    // pairs with the fence in reader::send_ingested, lines not seen here are sent
+    // by the reader with the new version
+    atomic::fence(Ordering::SeqCst);
+    let log_lines = app.logbuf.read();
+    let (first, len) = (log_lines.first(), log_lines.len());
+    let mut top = ui.log_offset.y.clamp(first, len);
+    let mut bottom = (top + ui.log_height).min(len);
+
+    let send = |lines| {
+        app.regex_channel
+            .send(WorkItem {
+                lines,
+                version,
+                ingested: false,
+            })
+            .unwrap()
+    };
+
+    if top < bottom {
+        send(top..bottom);
+    }
+    while top > first || bottom < len {
+        if bottom < len {
+            let end = (bottom + SEARCH_LINES).min(len);
+            send(bottom..end);
+            bottom = end;
+        }
+        if top > first {
+            let start = top.saturating_sub(SEARCH_LINES).max(first);
+            send(start..top);
+            top = start;
+        }
+    }
+}
+
+// Discards queued work of the search, new lines keep being searched
+fn cancel_search(app: &SharedState, ui: &mut UIState) {
+    if ui.search_started.is_none() {
+        return;
+    }
+    let ingested: Vec<WorkItem> = app
+        .regex_channel_recv
+        .try_iter()
+        .filter(|item| item.ingested)
+        .collect();
+    for item in ingested {
+        app.regex_channel.send(item).unwrap();
+    }
+    ui.search_cancelled = true;
+}
+
+fn schedule_search(ui: &mut UIState) {
+    ui.search_due = Some(Instant::now() + SEARCH_DEBOUNCE);
+}
+
+// starts the search scheduled while typing once typing paused
+pub fn start_due_search(app: &SharedState, ui: &mut UIState) {
+    if ui.search_due.is_some_and(|due| due <= Instant::now()) {
+        recompile_regex(app, ui);
+    }
+}
 
-    let re = Regex::new(&ui.search_query);
-    if let Ok(re) = re {
-        update_search(app, Some(re));
+// moves the search query to the filter stack
+fn push_filter(app: &SharedState, ui: &mut UIState, exclude: bool) {
+    let Ok(Some(query)) = Query::parse(&ui.search_query, ui.search_modes) else {
+        return;
+    };
+    ui.filters.push(Filter {
+        text: std::mem::take(&mut ui.search_query),
+        query,
+        exclude,
+        enabled: true,
+    });
+    recompile_regex(app, ui);
+}
+
+fn pop_filter(app: &SharedState, ui: &mut UIState) {
+    if ui.filters.pop().is_some() {
+        recompile_regex(app, ui);
     }
+}
 
-    {
-        let log_lines = app.logbuf.tmp_read();
+fn toggle_filter(app: &SharedState, ui: &mut UIState, filter: usize) {
+    if let Some(filter) = ui.filters.get_mut(filter) {
+        filter.enabled = !filter.enabled;
+        recompile_regex(app, ui);
+    }
+}
 
-        (0..log_lines.len()).step_by(10).for_each(|i| {
-            let end = std::cmp::min(i + 10, log_lines.len());
-            app.regex_channel.send((i, end)).unwrap();
-        });
+fn toggle_search_mode(app: &SharedState, ui: &mut UIState, mode: char) {
+    let modes = &mut ui.search_modes;
+    match mode {
+        'l' => modes.literal = !modes.literal,
+        'i' => modes.ignore_case = !modes.ignore_case,
+        's' => modes.smart_case = !modes.smart_case,
+        'w' => modes.whole_word = !modes.whole_word,
+        _ => return,
     }
+    recompile_regex(app, ui);
 }
 
 fn add_matches_scroll(app: &SharedState, ui: &mut UIState, value: isize) {
@@ -57,27 +263,262 @@ fn add_matches_scroll(app: &SharedState, ui: &mut UIState, value: isize) {
     }
 }
 
-fn add_log_scroll(_app: &SharedState, ui: &mut UIState, value: isize) {
-    ui.log_offset.y = ui.log_offset.y.saturating_add_signed(value);
+fn add_log_scroll(app: &SharedState, ui: &mut UIState, value: isize) {
+    let log_lines = app.logbuf.read();
+    let matches = app.matches.lock().unwrap();
+    let searching = app.search.read().unwrap().is_active();
+    let view = LogView::of_panel(&log_lines, ui, searching, &matches);
+    ui.log_offset.y = view.step(ui.log_offset.y, value);
+}
+
+fn toggle_source(app: &SharedState, ui: &mut UIState, source: usize) {
+    if source >= app.sources.len() {
+        return;
+    }
+    ui.hidden_sources ^= 1 << source;
+    recompile_regex(app, ui);
 }
 
 fn add_horizontal_scroll(_app: &SharedState, ui: &mut UIState, value: isize) {
-    ui.log_offset.x = ui.log_offset.x.saturating_add_signed(value);
+    set_horizontal_scroll(ui, ui.log_offset.x.saturating_add_signed(value));
+}
+
+fn set_horizontal_scroll(ui: &mut UIState, column: usize) {
+    ui.log_offset.x = column;
     ui.matches_offset.x = ui.log_offset.x;
 }
 
+// Jumps to a line number of the log, it is centered in the log panel
+fn jump_to_line(app: &SharedState, ui: &mut UIState, lineno: usize) {
+    let log_lines = app.logbuf.read();
+    if log_lines.len() == 0 {
+        return;
+    }
+    ui.following = false;
+    ui.log_cursor = lineno.clamp(log_lines.first(), log_lines.len() - 1);
+    ui.log_locate = Some(ui.log_cursor);
+}
+
+fn select_match(app: &SharedState, ui: &mut UIState, index: usize) {
+    let matches = app.matches.lock().unwrap();
+    if matches.is_empty() {
+        return;
+    }
+    ui.following = false;
+    ui.matches_should_locate = true;
+    let index = index.min(matches.len() - 1);
+    ui.matches_selected = Some(index);
+    ui.log_cursor = matches.get(index).unwrap().lineno;
+}
+
+// "find next match from here", selects the first match at or after the log cursor
+fn select_match_from_cursor(app: &SharedState, ui: &mut UIState) {
+    let index = {
+        let matches = app.matches.lock().unwrap();
+        let index = matches.partition_point(|m| m.lineno < ui.log_cursor);
+        if index == matches.len() {
+            return;
+        }
+        index
+    };
+    select_match(app, ui, index);
+}
+
+// Like n and N in less, selects the count-th match after or before the log position:
+// the log cursor, the selected match or the top of the log panel if nothing is selected.
+// Matches are sorted so they are found by binary search on line numbers.
+fn jump_to_match(app: &SharedState, ui: &mut UIState, count: isize) {
+    let index = {
+        let matches = app.matches.lock().unwrap();
+        let selected = ui
+            .matches_selected
+            .and_then(|i| matches.get(i))
+            .map(|m| m.lineno);
+        let position = match ui.selected_panel {
+            Panel::Log => Some(ui.log_cursor),
+            _ => selected,
+        };
+        let top = ui.log_offset.y;
+
+        if count > 0 {
+            // a match on the top line is the next one when nothing is selected
+            let next = match position {
+                Some(lineno) => matches.partition_point(|m| m.lineno <= lineno),
+                None => matches.partition_point(|m| m.lineno < top),
+            };
+            if next == matches.len() {
+                return;
+            }
+            next + count.unsigned_abs() - 1
+        } else {
+            let before = matches.partition_point(|m| m.lineno < position.unwrap_or(top));
+            if before == 0 {
+                return;
+            }
+            before.saturating_sub(count.unsigned_abs())
+        }
+    };
+    select_match(app, ui, index);
+}
+
+// Moves the log cursor, the log is scrolled along to keep the cursor shown
+fn move_log_cursor(app: &SharedState, ui: &mut UIState, lines: isize, scroll: isize) {
+    let log_lines = app.logbuf.read();
+    let matches = app.matches.lock().unwrap();
+    let searching = app.search.read().unwrap().is_active();
+    let view = LogView::of_panel(&log_lines, ui, searching, &matches);
+    let height = ui.log_height.max(1);
+    let shown = |top, lineno| {
+        view.window(top, height)
+            .iter()
+            .any(|row| matches!(row, Row::Line(i) if *i == lineno))
+    };
+
+    // the cursor starts from the top of the panel when scrolled away from it
+    if !shown(ui.log_offset.y, ui.log_cursor) {
+        ui.log_cursor = view.step(ui.log_offset.y, 0);
+    }
+    ui.following = false;
+    ui.log_offset.y = view.step(ui.log_offset.y, scroll);
+    ui.log_cursor = view.step(ui.log_cursor, lines);
+
+    if ui.log_cursor < ui.log_offset.y {
+        ui.log_offset.y = ui.log_cursor;
+    } else if !shown(ui.log_offset.y, ui.log_cursor) {
+        ui.log_offset.y = view.step(ui.log_cursor, 1 - height as isize);
+    }
+}
+
+// Line the horizontal motions go by: the log cursor, the selected match or the top line of the log
+fn reference_line(app: &SharedState, ui: &UIState) -> String {
+    let log_lines = app.logbuf.read();
+    let selected = ui
+        .matches_selected
+        .and_then(|i| app.matches.lock().unwrap().get(i).map(|m| m.lineno));
+    let lineno = match (ui.selected_panel, selected) {
+        (Panel::Log, _) => ui.log_cursor,
+        (_, Some(lineno)) => lineno,
+        (_, None) => ui.log_offset.y,
+    };
+    if lineno < log_lines.len() {
+        log_lines.line(lineno).to_string()
+    } else {
+        String::new()
+    }
+}
+
+fn is_word_start(line: &[char], i: usize) -> bool {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    is_word(line[i]) && (i == 0 || !is_word(line[i - 1]))
+}
+
+// column of the count-th word start after or before column, columns count characters
+fn word_column(line: &str, column: usize, count: isize) -> usize {
+    let line: Vec<char> = line.chars().collect();
+    let mut column = column.min(line.len());
+    for _ in 0..count.unsigned_abs() {
+        let next = if count > 0 {
+            (column + 1..line.len()).find(|&i| is_word_start(&line, i))
+        } else {
+            (0..column).rev().find(|&i| is_word_start(&line, i))
+        };
+        match next {
+            Some(next) => column = next,
+            None => break,
+        }
+    }
+    column
+}
+
+// Vertical motions move in the focused panel, horizontal ones scroll both panels
+fn apply_motion(app: &SharedState, ui: &mut UIState, motion: Motion) {
+    let height = match ui.selected_panel {
+        Panel::Log => ui.log_height,
+        _ => ui.matches_height,
+    };
+    let half_page = (height / 2).max(1) as isize;
+    let page = height.max(1) as isize;
+    match motion {
+        Motion::Lines(count) if ui.selected_panel == Panel::Log => {
+            move_log_cursor(app, ui, count, 0)
+        }
+        Motion::HalfPages(count) if ui.selected_panel == Panel::Log => {
+            move_log_cursor(app, ui, count * half_page, count * half_page)
+        }
+        Motion::Pages(count) if ui.selected_panel == Panel::Log => {
+            move_log_cursor(app, ui, count * page, count * page)
+        }
+        Motion::LogLines(count) if ui.selected_panel == Panel::Log => {
+            move_log_cursor(app, ui, count, count)
+        }
+        Motion::Lines(count) => add_matches_scroll(app, ui, count),
+        Motion::HalfPages(count) => add_matches_scroll(app, ui, count * half_page),
+        Motion::Pages(count) => add_matches_scroll(app, ui, count * page),
+        // line numbers of the log in every panel
+        Motion::GoTo(lineno) => jump_to_line(app, ui, lineno),
+        Motion::LogLines(count) => add_log_scroll(app, ui, count),
+        Motion::Columns(count) => add_horizontal_scroll(app, ui, count),
+        Motion::Words(count) => {
+            let column = word_column(&reference_line(app, ui), ui.log_offset.x, count);
+            set_horizontal_scroll(ui, column);
+        }
+        Motion::Matches(count) => jump_to_match(app, ui, count),
+        Motion::LineStart => set_horizontal_scroll(ui, 0),
+        // end of the line is shown at the right edge
+        Motion::LineEnd => {
+            let len = reference_line(app, ui).chars().count();
+            set_horizontal_scroll(ui, len.saturating_sub(ui.log_width));
+        }
+    }
+}
+
+// `:123` jumps to line 123
+fn process_command_key(key: KeyEvent, app: &SharedState, ui: &mut UIState) {
+    let Some(command) = &mut ui.command else {
+        return;
+    };
+    match key.code {
+        KeyCode::Char(c) if c.is_ascii_digit() => command.push(c),
+        KeyCode::Backspace if !command.is_empty() => {
+            command.pop();
+        }
+        KeyCode::Enter => {
+            if let Ok(line) = command.parse::<usize>() {
+                jump_to_line(app, ui, line.saturating_sub(1));
+            }
+            ui.command = None;
+        }
+        KeyCode::Esc | KeyCode::Backspace => ui.command = None,
+        _ => {}
+    }
+}
+
 pub fn process_key_event(key: KeyEvent, app: &SharedState, ui: &mut UIState) {
+    if ui.command.is_some() {
+        process_command_key(key, app, ui);
+        return;
+    }
+
     // common
-    #[allow(clippy::single_match)]
     match key.code {
         KeyCode::Tab => {
             match ui.selected_panel {
                 Panel::Search => ui.selected_panel = Panel::Matches,
-                Panel::Matches => {
+                Panel::Matches => ui.selected_panel = Panel::Log,
+                Panel::Log => {
                     ui.selected_panel = Panel::Search;
                 }
             };
         }
+        KeyCode::F(n @ 1..=9) => toggle_source(app, ui, n as usize - 1),
+        KeyCode::Char(c @ '1'..='9') if key.modifiers.contains(KeyModifiers::ALT) => {
+            toggle_filter(app, ui, c as usize - '1' as usize);
+            return;
+        }
+        KeyCode::Char(c @ ('l' | 'i' | 's' | 'w')) if key.modifiers.contains(KeyModifiers::ALT) => {
+            toggle_search_mode(app, ui, c);
+            return;
+        }
         _ => {}
     }
     match ui.selected_panel {
@@ -85,35 +526,50 @@ pub fn process_key_event(key: KeyEvent, app: &SharedState, ui: &mut UIState) {
             // TODO: vi mode ? how to best
             if let KeyCode::Char(c) = key.code {
                 ui.search_query.push(c);
-                recompile_regex(app, ui);
+                schedule_search(ui);
             } else if key.code == KeyCode::Backspace {
                 ui.search_query.pop();
-                recompile_regex(app, ui);
+                schedule_search(ui);
+            } else if key.code == KeyCode::Enter {
+                push_filter(app, ui, false);
             } else if key.code == KeyCode::Esc {
                 ui.selected_panel = Panel::Matches;
             }
         }
-        Panel::Matches => match key.code {
-            KeyCode::Char('j') => add_matches_scroll(app, ui, 1),
-            KeyCode::Char('k') => add_matches_scroll(app, ui, -1),
-            KeyCode::Char('d') => add_log_scroll(app, ui, 5),
-            KeyCode::Char('u') => add_log_scroll(app, ui, -5),
-            KeyCode::Char('l') => add_horizontal_scroll(app, ui, 3),
-            KeyCode::Char('h') => add_horizontal_scroll(app, ui, -3),
-            KeyCode::Char('q') => app
-                .should_quit
-                .store(true, std::sync::atomic::Ordering::Relaxed),
-            KeyCode::Char('c') => {
-                ui.search_query.clear();
-                ui.selected_panel = Panel::Search;
-            }
-            KeyCode::Char('i') => {
-                ui.selected_panel = Panel::Search;
-            }
-            KeyCode::Char('f') => {
-                ui.following = true;
-            }
-            _ => {}
+        Panel::Matches | Panel::Log => match ui.keys.feed(key) {
+            Step::Pending => {}
+            Step::Motion(motion) => apply_motion(app, ui, motion),
+            Step::Other => process_normal_key(key, app, ui),
         },
     }
 }
+
+// keys of the matches and log panels other than motions
+fn process_normal_key(key: KeyEvent, app: &SharedState, ui: &mut UIState) {
+    match key.code {
+        KeyCode::Enter if ui.selected_panel == Panel::Log => select_match_from_cursor(app, ui),
+        KeyCode::Char(':') => ui.command = Some(String::new()),
+        KeyCode::Char('#') => ui.line_numbers = !ui.line_numbers,
+        KeyCode::Char('q') => app
+            .should_quit
+            .store(true, std::sync::atomic::Ordering::Relaxed),
+        KeyCode::Char('c') => {
+            ui.search_query.clear();
+            ui.selected_panel = Panel::Search;
+        }
+        KeyCode::Char('i') => {
+            ui.selected_panel = Panel::Search;
+        }
+        KeyCode::Char('f') => {
+            ui.following = true;
+        }
+        KeyCode::Char('+') => push_filter(app, ui, false),
+        KeyCode::Char('-') => push_filter(app, ui, true),
+        KeyCode::Char('x') => pop_filter(app, ui),
+        KeyCode::Esc => cancel_search(app, ui),
+        KeyCode::Char('v') => ui.filtered_view = !ui.filtered_view,
+        KeyCode::Char('>') => ui.context_lines += 1,
+        KeyCode::Char('<') => ui.context_lines = ui.context_lines.saturating_sub(1),
+        _ => {}
+    }
+}
@@ -0,0 +1,51 @@
+use std::ops::Range;
+
+// Line ranges of the log already searched with the current search.
+// Kept as sorted disjoint ranges, neighbouring ones are merged.
+#[derive(Default)]
+pub struct Coverage {
+    version: usize,
+    // length of the log when the search started, lines added later are searched as they come
+    end: usize,
+    ranges: Vec<Range<usize>>,
+}
+
+impl Coverage {
+    // starts tracking a new search
+    pub fn reset(&mut self, version: usize, end: usize) {
+        self.version = version;
+        self.end = end;
+        self.ranges.clear();
+    }
+
+    // lines the search started with end here
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    // ranges searched by an older search are ignored
+    pub fn insert(&mut self, version: usize, range: Range<usize>) {
+        if version != self.version || range.is_empty() {
+            return;
+        }
+        // ranges touching the new one are merged into it
+        let from = self.ranges.partition_point(|r| r.end < range.start);
+        let to = self.ranges.partition_point(|r| r.start <= range.end);
+        let touching = &self.ranges[from..to];
+        let merged = match (touching.first(), touching.last()) {
+            (Some(first), Some(last)) => first.start.min(range.start)..last.end.max(range.end),
+            _ => range,
+        };
+        self.ranges.splice(from..to, [merged]);
+    }
+
+    // number of searched lines in range
+    pub fn covered(&self, range: Range<usize>) -> usize {
+        let from = self.ranges.partition_point(|r| r.end <= range.start);
+        self.ranges[from..]
+            .iter()
+            .take_while(|r| r.start < range.end)
+            .map(|r| r.end.min(range.end) - r.start.max(range.start))
+            .sum()
+    }
+}
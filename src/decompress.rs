@@ -0,0 +1,45 @@
+use std::io::{self, BufRead, BufReader};
+
+use flate2::bufread::MultiGzDecoder;
+
+// Compressed formats recognized by their magic bytes
+#[derive(Clone, Copy)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+    Bzip2,
+    Xz,
+}
+
+impl Compression {
+    pub fn detect(magic: &[u8]) -> Option<Self> {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else if magic.starts_with(b"BZh") {
+            Some(Compression::Bzip2)
+        } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Compression::Xz)
+        } else {
+            None
+        }
+    }
+
+    // Wraps the reader so it reads decompressed data, concatenated streams are read whole
+    pub fn decoder<R: BufRead + Send + 'static>(
+        self,
+        reader: R,
+    ) -> io::Result<Box<dyn BufRead + Send>> {
+        Ok(match self {
+            Compression::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(reader))),
+            Compression::Zstd => Box::new(BufReader::new(zstd::Decoder::with_buffer(reader)?)),
+            Compression::Bzip2 => {
+                Box::new(BufReader::new(bzip2::bufread::MultiBzDecoder::new(reader)))
+            }
+            Compression::Xz => Box::new(BufReader::new(
+                xz2::bufread::XzDecoder::new_multi_decoder(reader),
+            )),
+        })
+    }
+}
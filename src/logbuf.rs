@@ -1,45 +1,670 @@
-use std::{
-    ops::Range,
-    sync::{RwLock, RwLockReadGuard},
-};
-
-// A log buffer stores the raw log in single String and a list of line ranges.
-// Access is limited to one reading thread and one writing thread.
-// Should be cache friendly.
-// TODO: make it lock free
-#[allow(dead_code)]
+use std::{collections::VecDeque, mem, ops::Range, ptr, sync::Mutex};
+
+use crossbeam::epoch::{self, Guard};
+use memmap2::Mmap;
+
+use crate::appendvec::AppendVec;
+use crate::types::SourceId;
+
+// how many lines are indexed at once in a mapped file
+const INDEX_BATCH: usize = 64 * 1024;
+
+// both are small under Miri so tests reach eviction with few lines
+const SEGMENT_SIZE: usize = if cfg!(miri) { 1 << 8 } else { 1 << 20 };
+// most lines kept at once, line numbers themselves keep growing
+const MAX_LINES: usize = if cfg!(miri) { 1 << 10 } else { 1 << 32 };
+
+// How much of the log is kept in memory, oldest lines are evicted beyond that.
+// Lines of a mapped file are never evicted.
+#[derive(Default, Clone, Copy)]
+pub struct Limits {
+    pub max_lines: Option<usize>,
+    // bytes of line text and line locations
+    pub max_memory: Option<usize>,
+}
+
+// A log buffer stores the raw log in fixed size segments and a list of line locations.
+// It is append only: segments are never moved, so readers don't need any locks and only
+// see lines published before they started reading. Evicted segments are freed only after
+// every reader that could still see them is done.
+// Writers are serialized by a mutex that readers never touch.
 pub struct LogBuf {
-    raw: RwLock<String>,
-    lines: RwLock<Vec<Range<usize>>>,
+    lines: AppendVec<LineEntry>,
+    writer: Mutex<Writer>,
+    limits: Limits,
+    // smaller than SEGMENT_SIZE with a low memory limit, so a few segments fit in it
+    segment_size: usize,
+    // changes only when the source of written lines does
+    runs: Mutex<Runs>,
+
+    // set when the whole log is a single plain file, lines are then served from the mapping
+    mapped: Option<Mmap>,
+}
+
+#[derive(Clone, Copy)]
+struct LineEntry {
+    data: *const u8,
+    len: u32,
+    source: SourceId,
+}
+
+// SAFETY: points to a segment or the mapping, both are only read once the line is published
+unsafe impl Send for LineEntry {}
+unsafe impl Sync for LineEntry {}
+
+struct Segment {
+    data: *mut u8,
+    capacity: usize,
+    // number of the line after the last one stored in this segment
+    end: usize,
+}
+
+// SAFETY: segment memory is owned by the LogBuf, bytes are written only before the lines
+// using them are published
+unsafe impl Send for Segment {}
+
+impl Segment {
+    // SAFETY: no line of the segment can be read anymore
+    unsafe fn free(self) {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+            self.data,
+            self.capacity,
+        )));
+    }
+}
+
+struct Writer {
+    // oldest first, only the last one is being filled
+    segments: VecDeque<Segment>,
+    // bytes used in the last segment
+    used: usize,
+    // bytes of all segments
+    capacity: usize,
+    // end of the indexed part of the mapped file
+    mapped_end: usize,
+}
+
+// Runs of consecutive lines of the same source, used to skip lines of hidden sources
+// without looking at each of them.
+#[derive(Default)]
+struct Runs {
+    // first line of each run and its source, oldest first
+    runs: VecDeque<(usize, SourceId)>,
+    // runs evicted so far, run indexes count from the first run ever
+    evicted: usize,
+    // indexes of the runs of each source
+    by_source: Vec<VecDeque<usize>>,
+}
+
+impl Runs {
+    fn push(&mut self, lineno: usize, source: SourceId) {
+        if self.runs.back().is_some_and(|&(_, last)| last == source) {
+            return;
+        }
+        let index = self.evicted + self.runs.len();
+        self.runs.push_back((lineno, source));
+        let source = source as usize;
+        if self.by_source.len() <= source {
+            self.by_source.resize_with(source + 1, VecDeque::new);
+        }
+        self.by_source[source].push_back(index);
+    }
+
+    // drops runs ending before first
+    fn evict(&mut self, first: usize) {
+        while self.runs.len() > 1 && self.runs[1].0 <= first {
+            let (_, source) = self.runs.pop_front().unwrap();
+            self.by_source[source as usize].pop_front();
+            self.evicted += 1;
+        }
+    }
+
+    fn start(&self, run: usize) -> usize {
+        self.runs[run - self.evicted].0
+    }
+
+    // line after the run, the last run is still growing
+    fn end(&self, run: usize) -> usize {
+        self.runs
+            .get(run + 1 - self.evicted)
+            .map_or(usize::MAX, |&(start, _)| start)
+    }
+
+    // runs of sources not in hidden
+    fn visible(&self, hidden: u64) -> impl Iterator<Item = &VecDeque<usize>> {
+        self.by_source
+            .iter()
+            .enumerate()
+            .filter(move |&(source, _)| hidden & (1 << source) == 0)
+            .map(|(_, runs)| runs)
+    }
+
+    // first line at or after lineno of a source not in hidden
+    fn next(&self, lineno: usize, hidden: u64) -> Option<usize> {
+        self.visible(hidden)
+            .filter_map(|runs| {
+                let i = runs.partition_point(|&run| self.end(run) <= lineno);
+                runs.get(i).map(|&run| self.start(run).max(lineno))
+            })
+            .min()
+    }
+
+    // last line at or before lineno of a source not in hidden
+    fn prev(&self, lineno: usize, hidden: u64) -> Option<usize> {
+        self.visible(hidden)
+            .filter_map(|runs| {
+                let i = runs.partition_point(|&run| self.start(run) <= lineno);
+                let run = runs[i.checked_sub(1)?];
+                Some((self.end(run) - 1).min(lineno))
+            })
+            .max()
+    }
+}
+
+// Read access to the lines published before it was created.
+// Lines evicted meanwhile read as empty.
+pub struct LogReader<'a> {
+    buf: &'a LogBuf,
+    first: usize,
+    len: usize,
+    guard: Guard,
+}
+
+impl<'a> LogReader<'a> {
+    // oldest line kept in the log, lines before were evicted
+    pub fn first(&self) -> usize {
+        self.first
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    fn entry(&self, lineno: usize) -> Option<LineEntry> {
+        assert!(lineno < self.len, "line {lineno} not in log");
+        self.buf.lines.get(lineno, &self.guard)
+    }
+
+    // line without the line terminator
+    pub fn line(&self, lineno: usize) -> &str {
+        let Some(entry) = self.entry(lineno) else {
+            return "";
+        };
+        // SAFETY: published lines point to written bytes which are not freed while pinned,
+        // only valid utf-8 is stored and mapped lines are validated when indexed
+        unsafe {
+            std::str::from_utf8_unchecked(std::slice::from_raw_parts(
+                entry.data,
+                entry.len as usize,
+            ))
+        }
+    }
+
+    pub fn source(&self, lineno: usize) -> SourceId {
+        self.entry(lineno).map_or(0, |entry| entry.source)
+    }
+
+    // first line at or after lineno of a source not in hidden, None past the end
+    pub fn next_visible(&self, lineno: usize, hidden: u64) -> Option<usize> {
+        let lineno = lineno.max(self.first);
+        if lineno >= self.len {
+            return None;
+        }
+        if hidden == 0 {
+            return Some(lineno);
+        }
+        let runs = self.buf.runs.lock().unwrap();
+        runs.next(lineno, hidden).filter(|&i| i < self.len)
+    }
+
+    // last line at or before lineno of a source not in hidden, None before the first
+    pub fn prev_visible(&self, lineno: usize, hidden: u64) -> Option<usize> {
+        let lineno = lineno.min(self.len.checked_sub(1)?);
+        if lineno < self.first {
+            return None;
+        }
+        if hidden == 0 {
+            return Some(lineno);
+        }
+        let runs = self.buf.runs.lock().unwrap();
+        runs.prev(lineno, hidden).filter(|&i| i >= self.first)
+    }
+
+    fn line_len(&self, lineno: usize) -> usize {
+        self.entry(lineno).map_or(0, |entry| entry.len as usize)
+    }
+
+    // end of a chunk at the start of lines with about bytes of text, at least one line long
+    pub fn chunk_end(&self, lines: Range<usize>, bytes: usize) -> usize {
+        let mut size = 0;
+        let mut end = lines.start;
+        while end < lines.end && (end == lines.start || size < bytes) {
+            size += self.line_len(end) + 1;
+            end += 1;
+        }
+        end
+    }
+}
 
-    // temporary copy of the raw log
-    // used for code refactoring
-    // TODO: remove this
-    tmp_raw: RwLock<Vec<String>>,
+fn trim_newline(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
 }
 
-#[allow(dead_code)]
 impl LogBuf {
-    pub fn new() -> Self {
+    pub fn new(limits: Limits) -> Self {
         LogBuf {
-            raw: RwLock::new(String::new()),
-            lines: RwLock::new(Vec::new()),
-            tmp_raw: RwLock::new(Vec::new()),
+            lines: AppendVec::with_capacity(MAX_LINES),
+            writer: Mutex::new(Writer {
+                segments: VecDeque::new(),
+                used: 0,
+                capacity: 0,
+                mapped_end: 0,
+            }),
+            limits,
+            segment_size: limits
+                .max_memory
+                .map_or(SEGMENT_SIZE, |max| SEGMENT_SIZE.min(max / 8).max(1)),
+            runs: Mutex::new(Runs::default()),
+            mapped: None,
         }
     }
 
-    pub fn write(&self, data: String) {
-        let mut raw = self.raw.write().unwrap();
-        let mut tmp_raw = self.tmp_raw.write().unwrap();
-        raw.push_str(&data);
-        tmp_raw.push(data);
+    // Log backed by a mapped file, lines are added by index_mapped
+    pub fn mapped(map: Mmap) -> Self {
+        let mut logbuf = LogBuf::new(Limits::default());
+        logbuf.mapped = Some(map);
+        logbuf
     }
 
-    pub fn read<'a>(&'a self) -> RwLockReadGuard<'a, String> {
-        self.raw.read().unwrap()
+    pub fn is_mapped(&self) -> bool {
+        self.mapped.is_some()
     }
 
-    pub fn tmp_read<'a>(&'a self) -> RwLockReadGuard<'a, Vec<String>> {
-        self.tmp_raw.read().unwrap()
+    // oldest line kept in the log
+    pub fn first(&self) -> usize {
+        self.lines.first()
+    }
+
+    // Copies data to the last segment or to a new one if it does not fit.
+    // Takes the writer to make sure its lock is held.
+    fn store(&self, writer: &mut Writer, data: &[u8]) -> *const u8 {
+        let fits = writer
+            .segments
+            .back()
+            .is_some_and(|last| writer.used + data.len() <= last.capacity);
+        if !fits {
+            let capacity = self.segment_size.max(data.len());
+            writer.segments.push_back(Segment {
+                data: Box::into_raw(vec![0u8; capacity].into_boxed_slice()) as *mut u8,
+                capacity,
+                end: self.lines.len(),
+            });
+            writer.capacity += capacity;
+            writer.used = 0;
+        }
+
+        let segment = writer.segments.back_mut().unwrap();
+        // SAFETY: the range is inside the segment and not visible to readers yet
+        let start = unsafe { segment.data.add(writer.used) };
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), start, data.len()) };
+        writer.used += data.len();
+        // the line is published next
+        segment.end = self.lines.len() + 1;
+        start
+    }
+
+    // Takes the writer to make sure its lock is held
+    fn publish(&self, _writer: &mut Writer, entry: LineEntry) -> usize {
+        // runs are updated first so readers never see a line without its run
+        self.runs
+            .lock()
+            .unwrap()
+            .push(self.lines.len(), entry.source);
+        // SAFETY: writer lock is held
+        unsafe { self.lines.push(entry) }
+    }
+
+    // Evicts the oldest lines over the limits, segments are freed once no reader can see them
+    fn evict(&self, writer: &mut Writer) {
+        let len = self.lines.len();
+        let mut first = self.lines.first();
+        if let Some(max) = self.limits.max_lines {
+            first = first.max(len.saturating_sub(max));
+        }
+        if let Some(max) = self.limits.max_memory {
+            // oldest segments go first
+            let (mut oldest, mut freed) = (0, 0);
+            while writer.capacity - freed + (len - first) * mem::size_of::<LineEntry>() > max {
+                if oldest + 1 < writer.segments.len() {
+                    first = first.max(writer.segments[oldest].end);
+                    freed += writer.segments[oldest].capacity;
+                    oldest += 1;
+                } else {
+                    // a single segment left, line locations alone are over the limit
+                    let entries =
+                        max.saturating_sub(writer.capacity - freed) / mem::size_of::<LineEntry>();
+                    first = first.max(len.saturating_sub(entries));
+                    break;
+                }
+            }
+        }
+        // the line just written is kept even if it alone is over the limits
+        first = first.min(len - 1);
+        if first == self.lines.first() {
+            return;
+        }
+
+        let guard = epoch::pin();
+        // SAFETY: writer lock is held
+        unsafe { self.lines.evict(first, &guard) };
+        self.runs.lock().unwrap().evict(first);
+        let mut freed = false;
+        while writer.segments.len() > 1 && writer.segments[0].end <= first {
+            let segment = writer.segments.pop_front().unwrap();
+            writer.capacity -= segment.capacity;
+            // SAFETY: its lines are evicted, readers that could still see them are pinned
+            unsafe { guard.defer_unchecked(move || segment.free()) };
+            freed = true;
+        }
+        if freed {
+            guard.flush();
+        }
+    }
+
+    // Appends a line, returns its line number
+    pub fn write(&self, source: SourceId, data: &str) -> usize {
+        let mut writer = self.writer.lock().unwrap();
+        let start = self.store(&mut writer, data.as_bytes());
+        let lineno = self.publish(
+            &mut writer,
+            LineEntry {
+                data: start,
+                len: data.len() as u32,
+                source,
+            },
+        );
+        self.evict(&mut writer);
+        lineno
+    }
+
+    // Indexes the next batch of lines of a mapped file, returns their line numbers.
+    // Returns None when the whole file is indexed.
+    pub fn index_mapped(&self) -> Option<Range<usize>> {
+        let map = &self.mapped.as_ref()?[..];
+        let mut writer = self.writer.lock().unwrap();
+        let from = writer.mapped_end;
+        if from == map.len() {
+            return None;
+        }
+
+        let mut ends: Vec<usize> = memchr::memchr_iter(b'\n', &map[from..])
+            .take(INDEX_BATCH)
+            .map(|newline| from + newline + 1)
+            .collect();
+        if ends.len() < INDEX_BATCH && ends.last().copied().unwrap_or(from) < map.len() {
+            // last line without a line terminator
+            ends.push(map.len());
+        }
+
+        let first = self.lines.len();
+        let mut start = from;
+        for end in ends {
+            let line = trim_newline(&map[start..end]);
+            let entry = match std::str::from_utf8(line) {
+                Ok(_) => LineEntry {
+                    data: line.as_ptr(),
+                    len: line.len() as u32,
+                    source: 0,
+                },
+                Err(_) => {
+                    // not valid utf-8, a lossy copy is kept in memory instead
+                    let lossy = String::from_utf8_lossy(line);
+                    LineEntry {
+                        data: self.store(&mut writer, lossy.as_bytes()),
+                        len: lossy.len() as u32,
+                        source: 0,
+                    }
+                }
+            };
+            self.publish(&mut writer, entry);
+            start = end;
+        }
+        writer.mapped_end = start;
+
+        Some(first..self.lines.len())
+    }
+
+    pub fn read(&self) -> LogReader<'_> {
+        let guard = epoch::pin();
+        // first never passes len, both only grow
+        let first = self.lines.first();
+        LogReader {
+            buf: self,
+            first,
+            len: self.lines.len(),
+            guard,
+        }
+    }
+}
+
+impl Drop for LogBuf {
+    fn drop(&mut self) {
+        let writer = self.writer.get_mut().unwrap();
+        for segment in writer.segments.drain(..) {
+            // SAFETY: no readers are left
+            unsafe { segment.free() };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use regex::Regex;
+
+    use super::*;
+
+    const BENCH_LINES: usize = 2_000_000;
+
+    fn bench_line(i: usize) -> String {
+        format!(
+            "2023-10-11T13:14:{:02}.{:03}Z INFO request {i} served by api-{} in {}ms",
+            i / 1000 % 60,
+            i % 1000,
+            i % 7,
+            i * 7919 % 1000
+        )
+    }
+
+    // resident memory of the process in bytes
+    fn resident() -> usize {
+        let statm = fs::read_to_string("/proc/self/statm").unwrap();
+        let pages: usize = statm.split_whitespace().nth(1).unwrap().parse().unwrap();
+        pages * 4096
+    }
+
+    // best time of a few runs and the result
+    fn fastest<T>(f: impl Fn() -> T) -> (Duration, T) {
+        (0..3)
+            .map(|_| {
+                let start = Instant::now();
+                let result = f();
+                (start.elapsed(), result)
+            })
+            .min_by_key(|(elapsed, _)| *elapsed)
+            .unwrap()
+    }
+
+    // Compares the log buffer with a line per String, as the log was kept before.
+    // Run it alone so other tests don't change the resident memory:
+    // cargo test --release logbuf_vs_strings -- --ignored --nocapture
+    #[test]
+    #[ignore = "benchmark"]
+    fn logbuf_vs_strings() {
+        let regex = Regex::new(r"in 99\dms").unwrap();
+
+        // segments are mapped on their own, so they are given back when dropped
+        let before = resident();
+        let logbuf = LogBuf::new(Limits::default());
+        for i in 0..BENCH_LINES {
+            logbuf.write(0, &bench_line(i));
+        }
+        let logbuf_memory = resident() - before;
+        let lines = logbuf.read();
+        let (logbuf_time, logbuf_count) = fastest(|| {
+            (lines.first()..lines.len())
+                .filter(|&i| regex.is_match(lines.line(i)))
+                .count()
+        });
+        drop(lines);
+        drop(logbuf);
+
+        let before = resident();
+        let strings: Vec<String> = (0..BENCH_LINES).map(bench_line).collect();
+        let strings_memory = resident() - before;
+        let (strings_time, strings_count) =
+            fastest(|| strings.iter().filter(|line| regex.is_match(line)).count());
+
+        assert_eq!(logbuf_count, strings_count);
+        let text: usize = strings.iter().map(|line| line.len()).sum();
+        println!("{BENCH_LINES} lines, {} MiB of text", text >> 20);
+        println!(
+            "logbuf:  {:>4} MiB, searched in {logbuf_time:?}",
+            logbuf_memory >> 20
+        );
+        println!(
+            "strings: {:>4} MiB, searched in {strings_time:?}",
+            strings_memory >> 20
+        );
+    }
+
+    fn stress_line(lineno: usize) -> String {
+        format!("line {lineno} {}", "x".repeat(lineno % 50))
+    }
+
+    // One writer appends lines and evicts the oldest ones while readers check every line
+    // they can see, evicted lines read as empty and their segments are freed meanwhile.
+    // Together with the AppendVec one it runs under Miri too, crossbeam needs tree borrows
+    // and the global epoch collector leaves deferred frees behind at exit:
+    // MIRIFLAGS="-Zmiri-tree-borrows -Zmiri-ignore-leaks" cargo +nightly miri test concurrent
+    #[test]
+    fn concurrent_write_and_read() {
+        let (count, limits) = if cfg!(miri) {
+            (
+                300,
+                Limits {
+                    max_lines: Some(200),
+                    max_memory: Some(8 * SEGMENT_SIZE),
+                },
+            )
+        } else {
+            (
+                300_000,
+                Limits {
+                    max_lines: Some(100_000),
+                    max_memory: Some(4 * SEGMENT_SIZE),
+                },
+            )
+        };
+        let logbuf = LogBuf::new(limits);
+        let done = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| {
+                    let (mut last_first, mut last_len) = (0, 0);
+                    while !done.load(Ordering::Relaxed) {
+                        let lines = logbuf.read();
+                        assert!(lines.first() >= last_first && lines.len() >= last_len);
+                        assert!(lines.first() <= lines.len());
+                        (last_first, last_len) = (lines.first(), lines.len());
+                        // the newest lines and the oldest ones racing with eviction
+                        let newest = lines.len().saturating_sub(64)..lines.len();
+                        let oldest = lines.first()..(lines.first() + 64).min(lines.len());
+                        for lineno in oldest.chain(newest) {
+                            // each read might be the first after the line was evicted
+                            let line = lines.line(lineno);
+                            let evicted = || lineno < logbuf.first();
+                            assert!(line == stress_line(lineno) || line.is_empty() && evicted());
+                            let source = lines.source(lineno);
+                            assert!(source == (lineno % 3) as SourceId || evicted());
+                        }
+                    }
+                });
+            }
+
+            for lineno in 0..count {
+                let line = stress_line(lineno);
+                assert_eq!(logbuf.write((lineno % 3) as SourceId, &line), lineno);
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+
+        let lines = logbuf.read();
+        assert_eq!(lines.len(), count);
+        assert!(lines.len() - lines.first() <= limits.max_lines.unwrap());
+        for lineno in lines.first()..lines.len() {
+            assert_eq!(lines.line(lineno), stress_line(lineno));
+        }
+    }
+
+    #[test]
+    fn small_limits_keep_the_newest_lines() {
+        let memory = |max_memory| Limits {
+            max_lines: None,
+            max_memory: Some(max_memory),
+        };
+        for limits in [memory(1 << 20), memory(1 << 16)] {
+            let logbuf = LogBuf::new(limits);
+            for i in 0..10 {
+                logbuf.write(0, &stress_line(i));
+            }
+            let lines = logbuf.read();
+            assert_eq!((lines.first(), lines.len()), (0, 10));
+        }
+
+        let lines = Limits {
+            max_lines: Some(0),
+            max_memory: None,
+        };
+        // only the newest three lines fit in 100 bytes together with their locations
+        for (limits, kept) in [(memory(0), 1), (memory(100), 3), (lines, 1)] {
+            let logbuf = LogBuf::new(limits);
+            for i in 0..10 {
+                logbuf.write(0, &stress_line(i));
+                assert_eq!(logbuf.read().line(i), stress_line(i));
+            }
+            assert_eq!(logbuf.first(), 10 - kept);
+        }
+    }
+
+    #[test]
+    fn visible_lines_skip_hidden_runs() {
+        let logbuf = LogBuf::new(Limits {
+            max_lines: Some(100),
+            max_memory: None,
+        });
+        // runs of 1 to 4 lines of sources 0 to 2
+        for i in 0..300usize {
+            logbuf.write((i / (i % 4 + 1) % 3) as SourceId, "line");
+        }
+
+        let lines = logbuf.read();
+        for hidden in 0..8u64 {
+            let visible = |i: &usize| hidden & (1 << lines.source(*i)) == 0;
+            for lineno in 0..lines.len() + 2 {
+                let next = (lineno.max(lines.first())..lines.len()).find(visible);
+                assert_eq!(lines.next_visible(lineno, hidden), next, "next of {lineno}");
+                let prev = (lines.first()..=lineno.min(lines.len() - 1))
+                    .rev()
+                    .find(visible);
+                assert_eq!(lines.prev_visible(lineno, hidden), prev, "prev of {lineno}");
+            }
+        }
     }
 }
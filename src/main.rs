@@ -1,34 +1,183 @@
+use std::fs::File;
+use std::mem::MaybeUninit;
+use std::num::NonZeroUsize;
+use std::os::fd::{IntoRawFd, RawFd};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::Ordering;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 use std::{cmp, thread};
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 
 #[cfg(debug_assertions)]
 use better_panic::Settings;
 
+use clap::Parser;
 use crossbeam::channel;
-use crossterm::event::{self, KeyCode};
+use crossterm::event::{self, Event, KeyCode};
 use crossterm::terminal::{
     disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
 };
 use crossterm::{event::Event::Key, execute};
+use logbuf::{Limits, LogBuf};
+use merger::{merger_thread, MergeEvent};
 use ratatui::prelude::{CrosstermBackend, Terminal};
-use reader::reader_thread;
-use signal_hook::consts::{SIGHUP, SIGINT, SIGQUIT, SIGTERM};
+use reader::{index_thread, map_input, open_source, reader_thread, Input};
+use signal_hook::consts::{SIGBUS, SIGHUP, SIGINT, SIGQUIT, SIGTERM};
 use signal_hook::iterator::{Signals, SignalsInfo};
 use sorter::sorter_thread;
-use types::{Match, SharedState, UIState};
+use types::{Match, SharedState, Source, SourceId, UIState, WorkItem, MAX_SOURCES};
 
+mod appendvec;
 mod control;
+mod coverage;
+mod decompress;
 mod logbuf;
+mod matches;
+mod merger;
+mod query;
 mod reader;
 mod sorter;
+mod timestamp;
 mod types;
 mod ui;
+mod view;
 mod worker;
 
+#[derive(Parser)]
+#[command(version, about)]
+struct Args {
+    /// Log files to browse, stdin is read if none are given
+    files: Vec<PathBuf>,
+
+    /// Keep reading files as they grow, reopening them when rotated (like tail -F)
+    #[arg(short, long)]
+    follow: bool,
+
+    /// Keep at most this many lines, older ones are dropped
+    #[arg(long, value_name = "LINES")]
+    max_lines: Option<usize>,
+
+    /// Keep at most this much log in memory, older lines are dropped (e.g. 500M or 2G)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_memory: Option<usize>,
+
+    /// Number of threads searching the log [default: number of CPUs - 1]
+    #[arg(long, value_name = "COUNT")]
+    threads: Option<NonZeroUsize>,
+}
+
+// Parses a byte count with an optional K, M or G suffix
+fn parse_size(value: &str) -> Result<usize, String> {
+    let (digits, unit) = match value.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => value.split_at(i),
+        None => (value, ""),
+    };
+    let shift = match unit.to_ascii_uppercase().trim_end_matches(['B', 'I']) {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        _ => return Err(format!("unknown unit {unit}")),
+    };
+    let count: usize = digits.parse().map_err(|e| format!("{e}"))?;
+    count
+        .checked_mul(1 << shift)
+        .ok_or_else(|| String::from("size too large"))
+}
+
+// redraws requested by other threads are at most this often
+const FRAME: Duration = Duration::from_millis(16);
+// the UI is redrawn at least this often, e.g. for the search ETA
+const TICK: Duration = Duration::from_secs(1);
+
+const COMPRESSED_EXTENSIONS: [&str; 4] = ["gz", "zst", "bz2", "xz"];
+
+// Splits app.log.2.gz into app.log and generation 2, the current file is generation 0
+fn rotation(path: &Path) -> (PathBuf, u32) {
+    let mut base = path.to_path_buf();
+    if let Some(ext) = base.extension() {
+        if COMPRESSED_EXTENSIONS.iter().any(|c| ext == *c) {
+            base.set_extension("");
+        }
+    }
+    if let Some(generation) = base.extension().and_then(|e| e.to_str()?.parse().ok()) {
+        base.set_extension("");
+        return (base, generation);
+    }
+    (base, 0)
+}
+
+impl Args {
+    // rotated generations of the same log are read as one source
+    fn sources(&self) -> Vec<Source> {
+        if self.files.is_empty() {
+            return vec![Source::Stdin];
+        }
+
+        let mut groups: Vec<(PathBuf, Vec<(u32, PathBuf)>)> = Vec::new();
+        for path in self.files.iter() {
+            let (base, generation) = rotation(path);
+            match groups.iter_mut().find(|(b, _)| *b == base) {
+                Some((_, paths)) => paths.push((generation, path.clone())),
+                None => groups.push((base, vec![(generation, path.clone())])),
+            }
+        }
+
+        groups
+            .into_iter()
+            .map(|(name, mut paths)| {
+                // oldest generation first, stable for duplicates
+                paths.sort_by_key(|(generation, _)| cmp::Reverse(*generation));
+                Source::Files {
+                    name,
+                    paths: paths.into_iter().map(|(_, path)| path).collect(),
+                }
+            })
+            .collect()
+    }
+}
+
+// terminal and its settings from before raw mode, for restoring it from a signal handler
+static TERMINAL: OnceLock<(RawFd, libc::termios)> = OnceLock::new();
+
+fn save_terminal() {
+    let Ok(tty) = File::options().read(true).write(true).open("/dev/tty") else {
+        return;
+    };
+    // kept open for the signal handler
+    let fd = tty.into_raw_fd();
+    let mut termios = MaybeUninit::uninit();
+    // SAFETY: tcgetattr fills termios when it succeeds
+    if unsafe { libc::tcgetattr(fd, termios.as_mut_ptr()) } == 0 {
+        let _ = TERMINAL.set((fd, unsafe { termios.assume_init() }));
+    }
+}
+
+// Reading a mapped file past its end raises SIGBUS once the file is truncated by someone else
+// (logrotate copytruncate, `> file`). There is no way to go on, so the terminal is restored
+// and apyr exits with a message instead of dying in raw mode.
+fn exit_on_sigbus(source: &Source) -> Result<()> {
+    let message = format!(
+        "\x1b[?1049l\x1b[?25h\r\napyr: {source} was truncated while mapped to memory, \
+         use --follow for logs still being written to\n"
+    );
+    // SAFETY: only async-signal-safe functions are called and nothing is allocated
+    unsafe {
+        signal_hook::low_level::register(SIGBUS, move || {
+            if let Some((fd, termios)) = TERMINAL.get() {
+                libc::tcsetattr(*fd, libc::TCSANOW, termios);
+            }
+            libc::write(libc::STDERR_FILENO, message.as_ptr().cast(), message.len());
+            signal_hook::low_level::exit(1);
+        })?;
+    }
+    Ok(())
+}
+
 fn startup() -> Result<()> {
+    save_terminal();
     enable_raw_mode()?;
     execute!(std::io::stderr(), EnterAlternateScreen)?;
     Ok(())
@@ -67,39 +216,148 @@ pub fn initialize_panic_handler() {
 }
 
 // App update function
-fn process_event(app: &Arc<SharedState>, ui: &mut UIState) -> Result<()> {
-    if event::poll(std::time::Duration::from_millis(16))? {
-        if let Key(key) = event::read()? {
-            if key.kind == event::KeyEventKind::Press {
-                if key.modifiers == event::KeyModifiers::CONTROL && key.code == KeyCode::Char('c') {
-                    app.should_quit.store(true, Ordering::Relaxed);
-                } else {
-                    control::process_key_event(key, app, ui);
-                }
+fn process_event(app: &Arc<SharedState>, ui: &mut UIState, event: Event) {
+    if let Key(key) = event {
+        if key.kind == event::KeyEventKind::Press {
+            if key.modifiers == event::KeyModifiers::CONTROL && key.code == KeyCode::Char('c') {
+                app.should_quit.store(true, Ordering::Relaxed);
+            } else {
+                control::process_key_event(key, app, ui);
             }
         }
     }
-    Ok(())
 }
 
-fn run(mut signals: SignalsInfo) -> Result<()> {
+// Terminal events are read here so the UI can wait for them together with other wakeups.
+// Stops when the terminal is gone, dropping the sender tells the UI to quit.
+fn input_thread(events: channel::Sender<Event>) {
+    while let Ok(event) = event::read() {
+        if events.send(event).is_err() {
+            return;
+        }
+    }
+}
+
+// quits on the first signal, the UI might be waiting so it is woken up
+fn signal_thread(app: Arc<SharedState>, mut signals: SignalsInfo) {
+    if signals.forever().next().is_some() {
+        app.should_quit.store(true, Ordering::Relaxed);
+        app.request_redraw();
+    }
+}
+
+// Waits until the UI has to be drawn again: on input, on a redraw request or on the tick.
+// Redraw requests are delayed to the next frame so heavy ingestion does not redraw all the time.
+fn wait_for_redraw(
+    app: &Arc<SharedState>,
+    ui: &mut UIState,
+    events: &channel::Receiver<Event>,
+    drawn: Instant,
+) {
+    // a typed search starts without input
+    let deadline = ui
+        .search_due
+        .map_or(drawn + TICK, |due| due.min(drawn + TICK));
+    let event = channel::select! {
+        recv(events) -> event => event.ok(),
+        recv(app.redraw_recv) -> _ => {
+            if app.should_quit.load(Ordering::Relaxed) {
+                return;
+            }
+            // redraw requests coming meanwhile are left for the next frame
+            match events.recv_deadline(drawn + FRAME) {
+                Err(channel::RecvTimeoutError::Timeout) => return,
+                event => event.ok(),
+            }
+        }
+        default(deadline.saturating_duration_since(Instant::now())) => return,
+    };
+    match event {
+        Some(event) => process_event(app, ui, event),
+        // input thread is gone with the terminal
+        None => app.should_quit.store(true, Ordering::Relaxed),
+    }
+}
+
+fn run(signals: SignalsInfo, args: Args, mut inputs: Vec<Input>) -> Result<()> {
     let mut t = Terminal::new(CrosstermBackend::new(std::io::stderr()))?;
 
     let mut uistate = UIState::default();
 
     let mut regex_threads = Vec::new();
 
-    let (re_send, re_recv) = channel::unbounded::<(usize, usize)>();
+    let (re_send, re_recv) = channel::unbounded::<WorkItem>();
     let (match_send, match_recv) = channel::unbounded::<Vec<Match>>();
 
-    let app = Arc::new(SharedState::new(re_send, match_send, match_recv));
-    let app_handle = app.clone();
+    // a single plain file is browsed straight from memory mapping,
+    // its lines are never evicted so it is read as usual with limits
+    let limited = args.max_lines.is_some() || args.max_memory.is_some();
+    let mapped = if args.follow || limited || inputs.len() != 1 {
+        None
+    } else {
+        map_input(&mut inputs[0])?
+    };
+    let logbuf = match mapped {
+        Some(map) => {
+            exit_on_sigbus(&args.sources()[0])?;
+            LogBuf::mapped(map)
+        }
+        None => LogBuf::new(Limits {
+            max_lines: args.max_lines,
+            max_memory: args.max_memory,
+        }),
+    };
+
+    let app = Arc::new(SharedState::new(
+        args.sources(),
+        logbuf,
+        re_send,
+        re_recv.clone(),
+        match_send,
+        match_recv,
+    ));
+
+    if app.logbuf.is_mapped() {
+        let app_handle = app.clone();
+        thread::Builder::new()
+            .name("indexer".to_string())
+            .spawn(move || index_thread(app_handle))
+            .unwrap();
+        inputs.clear();
+    }
+
+    // multiple sources are interleaved by timestamp
+    let merge_send = if inputs.len() > 1 {
+        let (merge_send, merge_recv) = channel::unbounded::<MergeEvent>();
+        let app_handle = app.clone();
+        thread::Builder::new()
+            .name("merger".to_string())
+            .spawn(move || merger_thread(app_handle, merge_recv))
+            .unwrap();
+        Some(merge_send)
+    } else {
+        None
+    };
 
     // reader can be permamently blocked by stdin().read_line() so we don't join it
-    thread::Builder::new()
-        .name("reader".to_string())
-        .spawn(move || reader_thread(app_handle))
-        .unwrap();
+    for (source, input) in inputs.into_iter().enumerate() {
+        let app_handle = app.clone();
+        let merge_handle = merge_send.clone();
+        thread::Builder::new()
+            .name(format!("reader-{}", source))
+            .spawn(move || {
+                reader_thread(
+                    app_handle,
+                    source as SourceId,
+                    input,
+                    args.follow,
+                    merge_handle,
+                )
+            })
+            .unwrap();
+    }
+    // merger stops once all readers are done
+    drop(merge_send);
 
     let app_handle = app.clone();
     thread::Builder::new()
@@ -107,9 +365,13 @@ fn run(mut signals: SignalsInfo) -> Result<()> {
         .spawn(move || sorter_thread(app_handle))
         .unwrap();
 
-    // TODO: hmmm
-    let parallelism: usize = thread::available_parallelism().unwrap().into();
-    let workers_num = cmp::max(1, parallelism.saturating_sub(3));
+    // a core is left for reading and the UI, they are idle most of the time while searching
+    let workers_num = match args.threads {
+        Some(threads) => threads.get(),
+        None => thread::available_parallelism()
+            .map_or(1, |cpus| cpus.get().saturating_sub(1))
+            .max(1),
+    };
 
     for i in 0..workers_num {
         let app_handle = app.clone();
@@ -120,15 +382,27 @@ fn run(mut signals: SignalsInfo) -> Result<()> {
         regex_threads.push(thread);
     }
 
+    let (event_send, event_recv) = channel::unbounded::<Event>();
+    thread::Builder::new()
+        .name("input".to_string())
+        .spawn(move || input_thread(event_send))
+        .unwrap();
+
+    let app_handle = app.clone();
+    thread::Builder::new()
+        .name("signals".to_string())
+        .spawn(move || signal_thread(app_handle, signals))
+        .unwrap();
+
     loop {
         t.draw(|f| {
             ui::render_ui(&app, &mut uistate, f);
         })?;
 
-        process_event(&app, &mut uistate)?;
+        wait_for_redraw(&app, &mut uistate, &event_recv, Instant::now());
+        control::start_due_search(&app, &mut uistate);
 
-        if app.should_quit.load(Ordering::Relaxed) || signals.pending().next().is_some() {
-            app.should_quit.store(true, Ordering::Relaxed);
+        if app.should_quit.load(Ordering::Relaxed) {
             break;
         }
     }
@@ -137,13 +411,25 @@ fn run(mut signals: SignalsInfo) -> Result<()> {
 }
 
 fn main() -> Result<()> {
+    let args = Args::parse();
+    if args.sources().len() > MAX_SOURCES {
+        bail!("at most {MAX_SOURCES} logs can be opened at once");
+    }
+
+    // open everything up front so errors are reported before entering the TUI
+    let inputs = args
+        .sources()
+        .iter()
+        .map(|source| open_source(source).with_context(|| format!("cannot open {source}")))
+        .collect::<Result<Vec<_>>>()?;
+
     let signals = Signals::new([SIGTERM, SIGHUP, SIGINT, SIGQUIT])?;
 
     initialize_panic_handler();
 
     startup()?;
 
-    let result = run(signals);
+    let result = run(signals, args, inputs);
 
     // teardown terminal before unwrapping Result of app run
     shutdown()?;
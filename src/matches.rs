@@ -0,0 +1,319 @@
+use std::cmp::Ordering;
+use std::ops::Range;
+
+use crate::types::Match;
+
+// neighbouring segments are joined while they stay this short
+const SEGMENT_LEN: usize = 4 * 1024;
+
+// Matches sorted by line number, kept in segments of disjoint line ranges.
+// A sorted batch from a worker is merged only with the segments it overlaps,
+// so adding it does not move matches of the rest of the log.
+#[derive(Default)]
+pub struct Matches {
+    segments: Vec<Vec<Match>>,
+    // count of matches up to the end of each segment
+    ends: Vec<usize>,
+}
+
+impl Matches {
+    pub fn len(&self) -> usize {
+        self.ends.last().copied().unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.segments.clear();
+        self.ends.clear();
+    }
+
+    // index of the first match of segment
+    fn start(&self, segment: usize) -> usize {
+        segment.checked_sub(1).map_or(0, |i| self.ends[i])
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Match> {
+        let segment = self.ends.partition_point(|&end| end <= index);
+        self.segments.get(segment)?.get(index - self.start(segment))
+    }
+
+    // Index of the first match pred is false for, like slice::partition_point.
+    // pred has to be true for the matches before it and false for the rest.
+    pub fn partition_point(&self, pred: impl Fn(&Match) -> bool) -> usize {
+        let segment = self.segments.partition_point(|s| pred(s.last().unwrap()));
+        match self.segments.get(segment) {
+            Some(matches) => self.start(segment) + matches.partition_point(&pred),
+            None => self.len(),
+        }
+    }
+
+    // matches with indexes in range, only segments of the range are visited
+    pub fn range(&self, range: Range<usize>) -> impl Iterator<Item = &Match> {
+        let segment = self.ends.partition_point(|&end| end <= range.start);
+        let skip = range.start.saturating_sub(self.start(segment));
+        self.segments[segment.min(self.segments.len())..]
+            .iter()
+            .flatten()
+            .skip(skip)
+            .take(range.len())
+    }
+
+    // Adds matches sorted by line number, lines already matched are skipped
+    pub fn insert(&mut self, batch: Vec<Match>) {
+        let (Some(first), Some(last)) = (batch.first(), batch.last()) else {
+            return;
+        };
+        let (first, last) = (first.lineno, last.lineno);
+
+        // segments the batch overlaps are merged with it
+        let from = self
+            .segments
+            .partition_point(|s| s.last().unwrap().lineno < first);
+        let to = self.segments.partition_point(|s| s[0].lineno <= last);
+        let merged = self
+            .segments
+            .drain(from..to)
+            .fold(batch, |merged, segment| merge(segment, merged));
+        self.segments.insert(from, merged);
+
+        // short segments are joined to keep their count low
+        let mut at = from;
+        if self.joinable(at) {
+            let next = self.segments.remove(at + 1);
+            self.segments[at].extend(next);
+        }
+        if at > 0 && self.joinable(at - 1) {
+            let segment = self.segments.remove(at);
+            at -= 1;
+            self.segments[at].extend(segment);
+        }
+        self.update_ends(at);
+    }
+
+    // Removes matches of lines before lineno, returns how many were removed
+    pub fn remove_before(&mut self, lineno: usize) -> usize {
+        let count = self.partition_point(|m| m.lineno < lineno);
+        if count == 0 {
+            return 0;
+        }
+        let whole = self
+            .segments
+            .partition_point(|s| s.last().unwrap().lineno < lineno);
+        self.segments.drain(..whole);
+        if let Some(segment) = self.segments.first_mut() {
+            let partial = segment.partition_point(|m| m.lineno < lineno);
+            segment.drain(..partial);
+        }
+        self.update_ends(0);
+        count
+    }
+
+    fn joinable(&self, segment: usize) -> bool {
+        match (self.segments.get(segment), self.segments.get(segment + 1)) {
+            (Some(a), Some(b)) => a.len() + b.len() <= SEGMENT_LEN,
+            _ => false,
+        }
+    }
+
+    // recounts ends from segment on
+    fn update_ends(&mut self, segment: usize) {
+        self.ends.truncate(segment);
+        let mut end = self.start(segment);
+        for matches in &self.segments[segment..] {
+            end += matches.len();
+            self.ends.push(end);
+        }
+    }
+}
+
+// merges two sorted runs of matches, matches of the same line are kept once
+fn merge(a: Vec<Match>, b: Vec<Match>) -> Vec<Match> {
+    let mut merged = Vec::with_capacity(a.len() + b.len());
+    let (mut a, mut b) = (a.into_iter().peekable(), b.into_iter().peekable());
+    loop {
+        let next = match (a.peek(), b.peek()) {
+            (Some(x), Some(y)) => match x.lineno.cmp(&y.lineno) {
+                Ordering::Less => a.next(),
+                Ordering::Greater => b.next(),
+                Ordering::Equal => {
+                    b.next();
+                    a.next()
+                }
+            },
+            (Some(_), None) => a.next(),
+            (None, _) => b.next(),
+        };
+        match next {
+            Some(m) => merged.push(m),
+            None => return merged,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    // xorshift, good enough to shuffle test data without a dependency
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    fn matched(lineno: usize, source: usize) -> Match {
+        Match {
+            lineno,
+            source: source as u8,
+            version: 0,
+        }
+    }
+
+    // the store has to behave like a sorted Vec without duplicate lines
+    #[test]
+    fn same_as_sorted_vec() {
+        let mut rng = Rng(0x2545f4914f6cdd1d);
+        let mut matches = Matches::default();
+        let mut naive: Vec<Match> = Vec::new();
+        let mut first = 0;
+
+        for round in 0..500 {
+            if rng.below(20) == 0 {
+                first += rng.below(2000);
+                let removed = naive.iter().filter(|m| m.lineno < first).count();
+                naive.retain(|m| m.lineno >= first);
+                assert_eq!(matches.remove_before(first), removed);
+            } else {
+                // a sorted batch of lines near each other, overlapping earlier ones
+                let start = first + rng.below(50_000);
+                let mut lineno = start;
+                let batch: Vec<Match> = (0..rng.below(3000))
+                    .map(|_| {
+                        lineno += 1 + rng.below(3);
+                        matched(lineno, round % 7)
+                    })
+                    .collect();
+                for m in &batch {
+                    if let Err(i) = naive.binary_search_by_key(&m.lineno, |m| m.lineno) {
+                        naive.insert(i, m.clone());
+                    }
+                }
+                matches.insert(batch);
+            }
+
+            assert_eq!(matches.len(), naive.len());
+            assert_eq!(matches.is_empty(), naive.is_empty());
+            let key = |m: &Match| (m.lineno, m.source);
+            for _ in 0..20 {
+                let i = rng.below(naive.len() + 1);
+                assert_eq!(matches.get(i).map(key), naive.get(i).map(key));
+                let end = (i + rng.below(100)).min(naive.len());
+                let range: Vec<_> = matches.range(i..end).map(key).collect();
+                let expected: Vec<_> = naive[i..end].iter().map(key).collect();
+                assert_eq!(range, expected);
+                let lineno = first + rng.below(60_000);
+                assert_eq!(
+                    matches.partition_point(|m| m.lineno < lineno),
+                    naive.partition_point(|m| m.lineno < lineno)
+                );
+            }
+        }
+        let all: Vec<_> = matches.range(0..matches.len()).map(|m| m.lineno).collect();
+        let expected: Vec<_> = naive.iter().map(|m| m.lineno).collect();
+        assert_eq!(all, expected);
+    }
+
+    #[test]
+    fn empty() {
+        let mut matches = Matches::default();
+        matches.insert(Vec::new());
+        assert!(matches.is_empty());
+        assert_eq!(matches.len(), 0);
+        assert!(matches.get(0).is_none());
+        assert_eq!(matches.range(0..10).count(), 0);
+        assert_eq!(matches.partition_point(|_| true), 0);
+        assert_eq!(matches.remove_before(100), 0);
+    }
+
+    // Batches of the even lines of chunks, arriving outward from the middle of the log
+    // like a search started at the viewport, shuffled a bit like workers finishing in turns.
+    fn batches(lines: usize, chunk: usize) -> Vec<Vec<Match>> {
+        let chunks = lines / chunk;
+        let mut order: Vec<usize> = (0..chunks)
+            .map(|i| match i % 2 {
+                0 => chunks / 2 + i / 2,
+                _ => chunks / 2 - 1 - i / 2,
+            })
+            .collect();
+        let mut rng = Rng(0x9e3779b97f4a7c15);
+        for i in 1..order.len() {
+            if rng.below(2) == 0 {
+                order.swap(i - 1, i);
+            }
+        }
+        order
+            .into_iter()
+            .map(|c| {
+                (c * chunk..(c + 1) * chunk)
+                    .filter(|l| l % 2 == 0)
+                    .map(|l| matched(l, 0))
+                    .collect()
+            })
+            .collect()
+    }
+
+    // Inserting the matches of a 10M line log with half of the lines matching, compared with
+    // inserting them one by one into a sorted Vec as before. The Vec is quadratic,
+    // so it gets a smaller log:
+    // cargo test --release matches_store -- --ignored --nocapture
+    #[test]
+    #[ignore = "benchmark"]
+    fn matches_store() {
+        // about CHUNK_BYTES of 100 byte lines
+        const CHUNK: usize = 625;
+
+        for lines in [200_000, 10_000_000] {
+            let batches = batches(lines, CHUNK);
+            let start = Instant::now();
+            let mut matches = Matches::default();
+            for batch in batches {
+                matches.insert(batch);
+            }
+            println!("store, {lines} lines: inserted in {:?}", start.elapsed());
+            assert_eq!(matches.len(), lines / 2);
+
+            let start = Instant::now();
+            let mut rng = Rng(1);
+            for _ in 0..1_000_000 {
+                let i = rng.below(matches.len());
+                assert_eq!(matches.get(i).unwrap().lineno, i * 2);
+            }
+            println!("store, {lines} lines: 1M gets in {:?}", start.elapsed());
+        }
+
+        let lines = 200_000;
+        let start = Instant::now();
+        let mut naive: Vec<Match> = Vec::new();
+        for batch in batches(lines, CHUNK) {
+            for m in batch {
+                if let Err(i) = naive.binary_search_by_key(&m.lineno, |m| m.lineno) {
+                    naive.insert(i, m);
+                }
+            }
+        }
+        println!(
+            "sorted vec, {lines} lines: inserted in {:?}",
+            start.elapsed()
+        );
+    }
+}
@@ -0,0 +1,179 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use crossbeam::channel;
+
+use crate::reader::{Ingest, EOF_MARKER};
+use crate::types::{SharedState, SourceId};
+
+// A timestamped line together with the lines following it that have no timestamp (stack traces)
+pub struct Entry {
+    pub timestamp: i64,
+    pub lines: Vec<String>,
+}
+
+pub enum MergeEvent {
+    Entry(SourceId, Entry),
+    // source has nothing more to read for now
+    Idle(SourceId),
+    // source read all of its input
+    Eof(SourceId),
+    // source will not send anything anymore
+    Done(SourceId),
+}
+
+#[derive(PartialEq)]
+enum SourceState {
+    Reading,
+    Idle,
+    Done,
+}
+
+struct SourceQueue {
+    entries: VecDeque<Entry>,
+    state: SourceState,
+    // EOF marker still to be written
+    eof: bool,
+}
+
+impl SourceQueue {
+    // an empty queue of a source that is still reading might get an older entry soon
+    fn blocks(&self) -> bool {
+        self.entries.is_empty() && self.state == SourceState::Reading
+    }
+}
+
+// This thread interleaves entries of multiple sources into the log ordered by timestamp.
+// Entries are held back until every source still being read has one to compare with.
+pub fn merger_thread(app: Arc<SharedState>, events: channel::Receiver<MergeEvent>) {
+    let mut queues: Vec<SourceQueue> = (0..app.sources.len())
+        .map(|_| SourceQueue {
+            entries: VecDeque::new(),
+            state: SourceState::Reading,
+            eof: false,
+        })
+        .collect();
+
+    let mut ingest = Ingest::new(app.clone());
+
+    // ends when all readers are gone
+    for event in events.iter() {
+        match event {
+            MergeEvent::Entry(source, entry) => {
+                let queue = &mut queues[source as usize];
+                queue.entries.push_back(entry);
+                queue.state = SourceState::Reading;
+            }
+            MergeEvent::Idle(source) => queues[source as usize].state = SourceState::Idle,
+            MergeEvent::Eof(source) => queues[source as usize].eof = true,
+            MergeEvent::Done(source) => queues[source as usize].state = SourceState::Done,
+        }
+
+        while !queues.iter().any(|q| q.blocks()) {
+            if !emit_oldest(&mut ingest, &mut queues) {
+                break;
+            }
+        }
+        // EOF markers go after everything read so far, not among lines of other sources
+        if queues
+            .iter()
+            .all(|q| q.entries.is_empty() && q.state != SourceState::Reading)
+        {
+            emit_eof_markers(&mut ingest, &mut queues);
+        }
+        // readers have nothing more for now
+        if events.is_empty() {
+            ingest.flush();
+        }
+    }
+
+    while emit_oldest(&mut ingest, &mut queues) {}
+    emit_eof_markers(&mut ingest, &mut queues);
+}
+
+fn emit_eof_markers(ingest: &mut Ingest, queues: &mut [SourceQueue]) {
+    for (source, queue) in queues.iter_mut().enumerate() {
+        if queue.eof && queue.entries.is_empty() {
+            ingest.push_line(source as SourceId, EOF_MARKER);
+            queue.eof = false;
+        }
+    }
+}
+
+// appends the oldest queued entry to the log, returns false if all queues are empty
+fn emit_oldest(ingest: &mut Ingest, queues: &mut [SourceQueue]) -> bool {
+    let oldest = queues
+        .iter()
+        .enumerate()
+        .filter_map(|(i, q)| q.entries.front().map(|e| (e.timestamp, i)))
+        .min();
+
+    let Some((_, source)) = oldest else {
+        return false;
+    };
+    let entry = queues[source].entries.pop_front().unwrap();
+    for line in entry.lines {
+        ingest.push_line(source as SourceId, &line);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logbuf::{Limits, LogBuf};
+    use crate::types::Source;
+
+    fn entry(timestamp: i64, line: &str) -> Entry {
+        Entry {
+            timestamp,
+            lines: vec![line.to_string()],
+        }
+    }
+
+    // a.log ends long before b.log is read to its end
+    #[test]
+    fn eof_markers_come_after_merged_lines() {
+        let (work_send, work_recv) = channel::unbounded();
+        let (match_send, match_recv) = channel::unbounded();
+        let app = Arc::new(SharedState::new(
+            vec![Source::Stdin, Source::Stdin],
+            LogBuf::new(Limits::default()),
+            work_send,
+            work_recv,
+            match_send,
+            match_recv,
+        ));
+
+        let (events, receiver) = channel::unbounded();
+        for event in [
+            MergeEvent::Entry(0, entry(1, "A1")),
+            MergeEvent::Entry(0, entry(3, "A3")),
+            MergeEvent::Eof(0),
+            MergeEvent::Done(0),
+            MergeEvent::Entry(1, entry(2, "B2")),
+            MergeEvent::Entry(1, entry(4, "B4")),
+            MergeEvent::Eof(1),
+            MergeEvent::Done(1),
+        ] {
+            events.send(event).unwrap();
+        }
+        drop(events);
+        merger_thread(app.clone(), receiver);
+
+        let log = app.logbuf.read();
+        let lines: Vec<_> = (0..log.len())
+            .map(|i| (log.source(i), log.line(i)))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                (0, "A1"),
+                (1, "B2"),
+                (0, "A3"),
+                (1, "B4"),
+                (0, EOF_MARKER),
+                (1, EOF_MARKER),
+            ]
+        );
+    }
+}
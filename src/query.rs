@@ -0,0 +1,411 @@
+use regex::Regex;
+
+// A search query: regexes combined with AND, OR and NOT (also &, | and !) and grouped by
+// parentheses, e.g. `(timeout | refused) AND NOT healthcheck`.
+// Words between operators form a single regex, spaces included. Parentheses are grouping
+// only when unbalanced within their word, so `(a|b)` stays a regex.
+#[derive(Clone)]
+pub struct Query {
+    expr: Expr,
+    // matches terms that are not negated, used to highlight lines
+    highlight: Option<Regex>,
+}
+
+// How terms of a query are turned into regexes
+#[derive(Default, Clone, Copy)]
+pub struct SearchModes {
+    // metacharacters are escaped
+    pub literal: bool,
+    pub ignore_case: bool,
+    // case insensitive unless the term has an uppercase letter
+    pub smart_case: bool,
+    pub whole_word: bool,
+}
+
+impl SearchModes {
+    // names of the enabled modes
+    pub fn names(&self) -> Vec<&'static str> {
+        [
+            (self.literal, "literal"),
+            (self.ignore_case, "ignore case"),
+            (self.smart_case, "smart case"),
+            (self.whole_word, "word"),
+        ]
+        .into_iter()
+        .filter_map(|(enabled, name)| enabled.then_some(name))
+        .collect()
+    }
+
+    // flags are part of the pattern so highlighting can combine terms
+    fn pattern(&self, term: &str) -> String {
+        let mut pattern = if self.literal {
+            regex::escape(term)
+        } else {
+            term.to_string()
+        };
+        if self.whole_word {
+            pattern = format!(r"\b(?:{pattern})\b");
+        }
+        let has_upper = term.chars().any(char::is_uppercase);
+        if self.ignore_case || (self.smart_case && !has_upper) {
+            pattern = format!("(?i){pattern}");
+        }
+        pattern
+    }
+}
+
+#[derive(Clone)]
+enum Expr {
+    Term(Regex),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn is_match(&self, line: &str) -> bool {
+        match self {
+            Expr::Term(re) => re.is_match(line),
+            Expr::Not(expr) => !expr.is_match(line),
+            Expr::And(a, b) => a.is_match(line) && b.is_match(line),
+            Expr::Or(a, b) => a.is_match(line) || b.is_match(line),
+        }
+    }
+
+    fn positive_terms<'a>(&'a self, negated: bool, terms: &mut Vec<&'a str>) {
+        match self {
+            Expr::Term(re) if !negated => terms.push(re.as_str()),
+            Expr::Term(_) => {}
+            Expr::Not(expr) => expr.positive_terms(!negated, terms),
+            Expr::And(a, b) | Expr::Or(a, b) => {
+                a.positive_terms(negated, terms);
+                b.positive_terms(negated, terms);
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy)]
+enum Token {
+    Open,
+    Close,
+    Not,
+    And,
+    Or,
+    // byte range of the query
+    Term(usize, usize),
+}
+
+impl Query {
+    // Returns None for an empty query
+    pub fn parse(text: &str, modes: SearchModes) -> Result<Option<Query>, String> {
+        let tokens = tokenize(text);
+        if tokens.is_empty() {
+            return Ok(None);
+        }
+
+        let mut parser = Parser {
+            text,
+            modes,
+            tokens,
+            position: 0,
+        };
+        let expr = parser.or()?;
+        // everything else is consumed by or
+        if parser.peek().is_some() {
+            return Err(String::from("unexpected )"));
+        }
+
+        let mut terms = Vec::new();
+        expr.positive_terms(false, &mut terms);
+        let highlight = match terms.as_slice() {
+            [] => None,
+            [term] => Some(Regex::new(term).unwrap()),
+            terms => {
+                let alternatives: Vec<String> = terms.iter().map(|t| format!("(?:{t})")).collect();
+                // might be too big even though every term alone is not
+                Regex::new(&alternatives.join("|")).ok()
+            }
+        };
+
+        Ok(Some(Query { expr, highlight }))
+    }
+
+    pub fn is_match(&self, line: &str) -> bool {
+        self.expr.is_match(line)
+    }
+
+    pub fn highlight(&self) -> Option<&Regex> {
+        self.highlight.as_ref()
+    }
+}
+
+// difference of opening and closing parentheses, escaped ones are skipped
+fn depth(word: &str) -> isize {
+    let mut depth = 0;
+    let mut escaped = false;
+    for c in word.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    depth
+}
+
+fn push(tokens: &mut Vec<Token>, token: Token) {
+    match (tokens.last_mut(), token) {
+        // adjacent words are one regex
+        (Some(Token::Term(_, end)), Token::Term(_, new_end)) => *end = new_end,
+        _ => tokens.push(token),
+    }
+}
+
+fn tokenize(text: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+
+    let words = text
+        .split_whitespace()
+        .map(|word| (word.as_ptr() as usize - text.as_ptr() as usize, word));
+    for (mut start, word) in words {
+        match word {
+            "AND" | "&" | "&&" => push(&mut tokens, Token::And),
+            "OR" | "|" | "||" => push(&mut tokens, Token::Or),
+            "NOT" | "!" => push(&mut tokens, Token::Not),
+            _ => {
+                let mut word = word;
+                loop {
+                    if word.len() > 1 && word.starts_with('!') {
+                        push(&mut tokens, Token::Not);
+                    } else if word.starts_with('(') && depth(word) > 0 {
+                        push(&mut tokens, Token::Open);
+                    } else {
+                        break;
+                    }
+                    word = &word[1..];
+                    start += 1;
+                }
+
+                let mut closing = 0;
+                while word.ends_with(')') && !word.ends_with("\\)") && depth(word) < 0 {
+                    word = &word[..word.len() - 1];
+                    closing += 1;
+                }
+
+                if !word.is_empty() {
+                    push(&mut tokens, Token::Term(start, start + word.len()));
+                }
+                for _ in 0..closing {
+                    push(&mut tokens, Token::Close);
+                }
+            }
+        }
+    }
+    tokens
+}
+
+// Recursive descent, NOT binds tighter than AND which binds tighter than OR
+struct Parser<'a> {
+    text: &'a str,
+    modes: SearchModes,
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<Token> {
+        self.tokens.get(self.position).copied()
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek();
+        self.position += 1;
+        token
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.peek() == Some(Token::Or) {
+            self.next();
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        loop {
+            match self.peek() {
+                Some(Token::And) => {
+                    self.next();
+                }
+                // terms next to each other without an operator have to match both
+                Some(Token::Not | Token::Open | Token::Term(..)) => {}
+                _ => return Ok(expr),
+            }
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.unary()?))),
+            Some(Token::Open) => {
+                let expr = self.or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expr),
+                    _ => Err(String::from("missing )")),
+                }
+            }
+            Some(Token::Term(start, end)) => {
+                let term = &self.text[start..end];
+                Regex::new(&self.modes.pattern(term))
+                    .map(Expr::Term)
+                    .map_err(|e| regex_error(term, e))
+            }
+            Some(Token::Close) => Err(String::from("unexpected )")),
+            _ => Err(String::from("missing search term")),
+        }
+    }
+}
+
+// only the last line of a syntax error, it fits the search panel title
+fn regex_error(term: &str, error: regex::Error) -> String {
+    match error {
+        regex::Error::Syntax(message) => {
+            let reason = message.lines().last().unwrap_or_default();
+            format!("{term}: {}", reason.trim_start_matches("error: "))
+        }
+        _ => format!("{term}: regex too big"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(text: &str) -> Query {
+        Query::parse(text, SearchModes::default()).unwrap().unwrap()
+    }
+
+    fn error(text: &str) -> String {
+        Query::parse(text, SearchModes::default()).err().unwrap()
+    }
+
+    #[test]
+    fn words_form_one_regex() {
+        let q = query("connection refused");
+        assert!(q.is_match("error: connection refused"));
+        assert!(!q.is_match("refused connection"));
+    }
+
+    #[test]
+    fn operators() {
+        let q = query("error AND NOT healthcheck");
+        assert!(q.is_match("error in handler"));
+        assert!(!q.is_match("error in healthcheck"));
+
+        let q = query("timeout | refused");
+        assert!(q.is_match("read timeout"));
+        assert!(q.is_match("refused"));
+        assert!(!q.is_match("ok"));
+
+        // NOT binds tighter than AND, AND tighter than OR
+        let q = query("a OR b AND NOT c");
+        assert!(q.is_match("a c"));
+        assert!(q.is_match("b"));
+        assert!(!q.is_match("b c"));
+    }
+
+    #[test]
+    fn negated_words() {
+        for text in ["error !debug", "error ! debug", "error NOT debug"] {
+            let q = query(text);
+            assert!(q.is_match("error"), "{text}");
+            assert!(!q.is_match("error debug"), "{text}");
+        }
+        // a word starting with ! is negated even if it is not a word
+        let q = query("!=");
+        assert!(q.is_match("a b"));
+        assert!(!q.is_match("a = b"));
+    }
+
+    #[test]
+    fn implicit_and() {
+        let q = query("(timeout | refused) !debug");
+        assert!(q.is_match("timeout"));
+        assert!(!q.is_match("timeout debug"));
+        assert!(!q.is_match("debug"));
+    }
+
+    #[test]
+    fn balanced_parentheses_are_regex() {
+        let q = query("(a|b)c");
+        assert!(q.is_match("bc"));
+        assert!(!q.is_match("(a|b)c"));
+
+        let q = query("(timeout | refused) AND service=payments");
+        assert!(q.is_match("refused service=payments"));
+        assert!(!q.is_match("refused service=orders"));
+
+        // a regex group at the start of a grouped word
+        let q = query("((a|b)x OR y)");
+        assert!(q.is_match("bx"));
+        assert!(q.is_match("y"));
+        assert!(!q.is_match("x"));
+    }
+
+    #[test]
+    fn escaped_parentheses() {
+        let q = query(r"(x OR f\))");
+        assert!(q.is_match("f)"));
+        assert!(!q.is_match("f"));
+        assert!(query(r"\(").is_match("("));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(Query::parse("  ", SearchModes::default())
+            .unwrap()
+            .is_none());
+        assert_eq!(error("a AND"), "missing search term");
+        assert_eq!(error("AND"), "missing search term");
+        assert_eq!(error("(a OR b"), "missing )");
+        assert_eq!(error("a)"), "unexpected )");
+        assert_eq!(error("["), "[: unclosed character class");
+    }
+
+    #[test]
+    fn highlight_skips_negated_terms() {
+        let q = query("a AND NOT b");
+        assert_eq!(q.highlight().unwrap().as_str(), "a");
+        let q = query("a OR NOT (b AND NOT c)");
+        assert_eq!(q.highlight().unwrap().as_str(), "(?:a)|(?:c)");
+        assert!(query("NOT b").highlight().is_none());
+    }
+
+    #[test]
+    fn modes() {
+        let smart = SearchModes {
+            smart_case: true,
+            ..Default::default()
+        };
+        let q = Query::parse("error", smart).unwrap().unwrap();
+        assert!(q.is_match("ERROR"));
+        let q = Query::parse("Error", smart).unwrap().unwrap();
+        assert!(!q.is_match("ERROR"));
+
+        let literal_word = SearchModes {
+            literal: true,
+            whole_word: true,
+            ..Default::default()
+        };
+        let q = Query::parse("a.b", literal_word).unwrap().unwrap();
+        assert!(q.is_match("x a.b y"));
+        assert!(!q.is_match("axb"));
+        assert!(!q.is_match("xa.b"));
+    }
+}
@@ -1,66 +1,455 @@
 use std::{
-    io,
-    sync::{atomic::Ordering, Arc},
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Stdin},
+    ops::Range,
+    os::unix::fs::MetadataExt,
+    path::PathBuf,
+    sync::{
+        atomic::{self, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
 };
 
+use crossbeam::channel;
 use crossterm::tty::IsTty;
+use memmap2::Mmap;
 
-use crate::types::SharedState;
+use crate::decompress::Compression;
+use crate::merger::{Entry, MergeEvent};
+use crate::timestamp;
+use crate::types::{SharedState, Source, SourceId, WorkItem, CHUNK_BYTES};
 
-// pub fn read_file() -> Vec<String> {
-//     let mut file = File::open("src/main.rs").unwrap();
-//     let mut contents = String::new();
-//     file.read_to_string(&mut contents).unwrap();
-//
-//     let mut lines = Vec::new();
-//     for line in contents.lines() {
-//         lines.push(line.to_string());
-//     }
-//
-//     lines
-// }
+// appended to the log once a source was read to its end
+pub const EOF_MARKER: &str = " ** EOF REACHED ** ";
+
+// lines without a timestamp are sent to the merger in entries of at most this many lines
+const MAX_ENTRY_LINES: usize = 1024;
+
+// how often a followed file is checked for new data
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(200);
+
+pub enum Input {
+    Stdin(BufReader<Stdin>),
+    // oldest first
+    Files(Vec<FileInput>),
+}
+
+// Input that knows whether it has data left without reading more
+trait Buffered: BufRead {
+    fn has_buffered(&self) -> bool;
+}
+
+impl<R: Read> Buffered for BufReader<R> {
+    fn has_buffered(&self) -> bool {
+        !self.buffer().is_empty()
+    }
+}
+
+// decompressed files do not wait for data
+impl Buffered for Box<dyn BufRead + Send> {
+    fn has_buffered(&self) -> bool {
+        true
+    }
+}
+
+pub struct FileInput {
+    path: PathBuf,
+    reader: BufReader<File>,
+    // device and inode of the opened file, used to detect rotation
+    id: (u64, u64),
+}
+
+impl FileInput {
+    fn open(path: PathBuf) -> io::Result<Self> {
+        let file = File::open(&path)?;
+        let meta = file.metadata()?;
+        Ok(FileInput {
+            path,
+            reader: BufReader::new(file),
+            id: (meta.dev(), meta.ino()),
+        })
+    }
+
+    // Maps a plain file to memory, None if it is compressed or empty
+    fn map(&mut self) -> io::Result<Option<Mmap>> {
+        if self.compression()?.is_some() || self.reader.get_ref().metadata()?.len() == 0 {
+            return Ok(None);
+        }
+        // SAFETY: the file must not be truncated while apyr runs, growing files are
+        // followed with regular reads instead. Truncation raises SIGBUS, which is
+        // handled by restoring the terminal and exiting.
+        let map = unsafe { Mmap::map(self.reader.get_ref())? };
+        Ok(Some(map))
+    }
 
-#[inline(always)]
-pub fn push_line(app: &Arc<SharedState>, line: String, line_count: usize) {
-    {
-        app.logbuf.write(line);
+    // looks at the first bytes without consuming them
+    fn compression(&mut self) -> io::Result<Option<Compression>> {
+        Ok(Compression::detect(self.reader.fill_buf()?))
     }
+}
+
+// Opens the source for reading, called before the UI starts so errors can be reported
+pub fn open_source(source: &Source) -> io::Result<Input> {
+    match source {
+        Source::Stdin => Ok(Input::Stdin(BufReader::new(io::stdin()))),
+        Source::Files { paths, .. } => Ok(Input::Files(
+            paths
+                .iter()
+                .map(|path| FileInput::open(path.clone()))
+                .collect::<io::Result<_>>()?,
+        )),
+    }
+}
+
+// Lines written to the log but not sent to workers yet.
+// They are sent in chunks of CHUNK_BYTES or earlier when flushed.
+pub struct Ingest {
+    app: Arc<SharedState>,
+    lines: Range<usize>,
+    bytes: usize,
+}
+
+impl Ingest {
+    pub fn new(app: Arc<SharedState>) -> Self {
+        Ingest {
+            app,
+            lines: 0..0,
+            bytes: 0,
+        }
+    }
+
+    #[inline(always)]
+    pub fn push_line(&mut self, source: SourceId, line: &str) {
+        let first = self.app.logbuf.first();
+        let lineno = self.app.logbuf.write(source, line);
+        if self.app.logbuf.first() != first {
+            drop_evicted_matches(&self.app);
+        }
+
+        if self.lines.is_empty() {
+            self.lines = lineno..lineno;
+        }
+        self.lines.end = lineno + 1;
+        self.bytes += line.len() + 1;
+        if self.bytes >= CHUNK_BYTES {
+            self.flush();
+        }
+    }
+
+    // sends the pending lines, called before waiting for more input
+    pub fn flush(&mut self) {
+        if !self.lines.is_empty() {
+            send_ingested(&self.app, std::mem::take(&mut self.lines));
+            self.bytes = 0;
+            self.app.request_redraw();
+        }
+    }
+}
+
+impl Drop for Ingest {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+// Sends lines just written to the log to workers.
+// The version is read after the lines were published, so either the search started
+// meanwhile sees them in the log or they are tagged with its version here.
+fn send_ingested(app: &SharedState, lines: Range<usize>) {
+    atomic::fence(Ordering::SeqCst);
+    let version = app.search_version.load(Ordering::SeqCst);
     app.regex_channel
-        .send((line_count, line_count + 1))
+        .send(WorkItem {
+            lines,
+            version,
+            ingested: true,
+        })
         .unwrap();
 }
 
-pub fn reader_thread(app: Arc<SharedState>) {
-    let mut line_count: usize = 0;
-    let stdin = io::stdin();
-    if stdin.is_tty() {
-        // no pipe, no stdin
-        push_line(&app, String::from(" ** EOF REACHED ** "), line_count);
-        return;
+// matches of evicted lines are dropped, the UI shifts its selection by their count
+fn drop_evicted_matches(app: &SharedState) {
+    let mut matches = app.matches.lock().unwrap();
+    let first = app.logbuf.first();
+    let evicted = matches.remove_before(first);
+    if evicted > 0 {
+        app.matches_dropped.fetch_add(evicted, Ordering::Relaxed);
     }
+}
 
-    loop {
-        if app.should_quit.load(Ordering::Relaxed) {
-            break;
+struct LineReader {
+    app: Arc<SharedState>,
+    ingest: Ingest,
+    source: SourceId,
+    // incomplete last line, kept until the rest of it is written
+    partial: Vec<u8>,
+    merge: Option<Merge>,
+}
+
+// Groups lines into entries for merging with other sources by timestamp
+struct Merge {
+    events: channel::Sender<MergeEvent>,
+    // entry still collecting lines without a timestamp
+    entry: Option<Entry>,
+    last_timestamp: i64,
+    idle: bool,
+}
+
+impl LineReader {
+    fn push(&mut self, line: String) {
+        let Some(merge) = &mut self.merge else {
+            self.ingest.push_line(self.source, &line);
+            return;
+        };
+        merge.idle = false;
+
+        match timestamp::parse(&line) {
+            Some(timestamp) => {
+                merge.send_entry(self.source);
+                merge.last_timestamp = timestamp;
+                merge.entry = Some(Entry {
+                    timestamp,
+                    lines: vec![line],
+                });
+            }
+            None => match &mut merge.entry {
+                Some(entry) => {
+                    entry.lines.push(line);
+                    // the merger waits for the entry meanwhile, the rest inherits its timestamp
+                    if entry.lines.len() >= MAX_ENTRY_LINES {
+                        merge.send_entry(self.source);
+                    }
+                }
+                None => {
+                    merge.entry = Some(Entry {
+                        timestamp: merge.last_timestamp,
+                        lines: vec![line],
+                    })
+                }
+            },
         }
-        let mut buffer = String::new();
+    }
 
-        let size = stdin.read_line(&mut buffer);
+    // nothing more to read right now, lets the merger go on without waiting for us
+    fn caught_up(&mut self) {
+        if let Some(merge) = &mut self.merge {
+            if !merge.idle {
+                merge.send_entry(self.source);
+                merge.events.send(MergeEvent::Idle(self.source)).unwrap();
+                merge.idle = true;
+            }
+        }
+    }
 
-        match size {
-            Ok(0) => {
-                push_line(&app, String::from(" ** EOF REACHED ** "), line_count);
-                break;
+    // the merger places the marker itself, it must not end up inside our last entry
+    fn end_of_input(&mut self) {
+        match &mut self.merge {
+            Some(merge) => {
+                merge.send_entry(self.source);
+                merge.events.send(MergeEvent::Eof(self.source)).unwrap();
             }
-            Ok(_) => {
-                push_line(&app, buffer, line_count);
-                line_count += 1;
+            None => self.ingest.push_line(self.source, EOF_MARKER),
+        }
+    }
+
+    fn flush_partial(&mut self) {
+        if !self.partial.is_empty() {
+            let line = self.partial.strip_suffix(b"\n").unwrap_or(&self.partial);
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            // logs are not always valid utf-8, don't choke on them
+            let line = String::from_utf8_lossy(line).into_owned();
+            self.partial.clear();
+            self.push(line);
+        }
+    }
+
+    // Reads lines until EOF, returns false if reading should not continue
+    fn read_available(&mut self, input: &mut dyn Buffered) -> bool {
+        loop {
+            if self.app.should_quit.load(Ordering::Relaxed) {
+                return false;
+            }
+            // reading might block, lines read so far are searched or merged meanwhile
+            if !input.has_buffered() {
+                self.ingest.flush();
+                if let Some(merge) = &mut self.merge {
+                    merge.send_entry(self.source);
+                }
+            }
+
+            match input.read_until(b'\n', &mut self.partial) {
+                Ok(0) => {
+                    self.ingest.flush();
+                    return true;
+                }
+                Ok(_) => {
+                    if self.partial.last() == Some(&b'\n') {
+                        self.flush_partial();
+                    }
+                }
+                Err(e) => {
+                    self.flush_partial();
+                    self.push(format!(" ** READ ERROR: {e} ** "));
+                    return false;
+                }
+            }
+        }
+    }
+
+    // Keeps reading the file as it grows, like tail -F.
+    // Truncated files are read again from the start, rotated files are reopened by path.
+    // Lines already read are never dropped.
+    fn follow(&mut self, mut input: FileInput) {
+        loop {
+            if !self.read_available(&mut input.reader) {
+                return;
+            }
+            self.caught_up();
+            thread::sleep(FOLLOW_INTERVAL);
+
+            let Ok(position) = input.reader.stream_position() else {
+                continue;
+            };
+            let Ok(opened) = input.reader.get_ref().metadata() else {
+                continue;
+            };
+            if opened.len() < position {
+                // truncated in place (copytruncate, `> file`)
+                self.flush_partial();
+                if input.reader.seek(SeekFrom::Start(0)).is_err() {
+                    return;
+                }
+                continue;
+            }
+
+            // file is missing while being rotated, wait for it to come back
+            let Ok(current) = fs::metadata(&input.path) else {
+                continue;
+            };
+            if (current.dev(), current.ino()) != input.id {
+                // renamed and recreated, finish the old file first
+                if !self.read_available(&mut input.reader) {
+                    return;
+                }
+                self.flush_partial();
+                if let Ok(reopened) = FileInput::open(input.path.clone()) {
+                    input = reopened;
+                }
+            }
+        }
+    }
+}
+
+impl Merge {
+    fn send_entry(&mut self, source: SourceId) {
+        if let Some(entry) = self.entry.take() {
+            self.events.send(MergeEvent::Entry(source, entry)).unwrap();
+        }
+    }
+}
+
+impl Drop for LineReader {
+    fn drop(&mut self) {
+        if let Some(merge) = &mut self.merge {
+            merge.send_entry(self.source);
+            // merger might be gone already when quitting
+            let _ = merge.events.send(MergeEvent::Done(self.source));
+        }
+    }
+}
+
+// Maps the input to memory if it is a single plain file
+pub fn map_input(input: &mut Input) -> io::Result<Option<Mmap>> {
+    match input {
+        Input::Files(files) if files.len() == 1 => files[0].map(),
+        _ => Ok(None),
+    }
+}
+
+// Indexes lines of a mapped log in the background, used instead of reader_thread
+pub fn index_thread(app: Arc<SharedState>) {
+    while let Some(lines) = app.logbuf.index_mapped() {
+        if app.should_quit.load(Ordering::Relaxed) {
+            return;
+        }
+        let log_lines = app.logbuf.read();
+        let mut start = lines.start;
+        while start < lines.end {
+            let end = log_lines.chunk_end(start..lines.end, CHUNK_BYTES);
+            send_ingested(&app, start..end);
+            start = end;
+        }
+        app.request_redraw();
+    }
+}
+
+// Reads a single source, there is one reader thread per source.
+// Lines are sent to the merger instead of the log if there are more sources.
+pub fn reader_thread(
+    app: Arc<SharedState>,
+    source: SourceId,
+    input: Input,
+    follow: bool,
+    merge: Option<channel::Sender<MergeEvent>>,
+) {
+    let mut reader = LineReader {
+        ingest: Ingest::new(app.clone()),
+        app,
+        source,
+        partial: Vec::new(),
+        merge: merge.map(|events| Merge {
+            events,
+            entry: None,
+            last_timestamp: i64::MIN,
+            idle: false,
+        }),
+    };
+
+    match input {
+        Input::Stdin(_) if io::stdin().is_tty() => {
+            // no pipe, no stdin
+        }
+        Input::Stdin(mut stdin) => {
+            if !reader.read_available(&mut stdin) {
+                return;
             }
-            Err(_) => {
-                todo!("Handle me!");
+            reader.flush_partial();
+        }
+        Input::Files(files) => {
+            let count = files.len();
+            for (i, mut file) in files.into_iter().enumerate() {
+                let compression = match file.compression() {
+                    Ok(compression) => compression,
+                    Err(e) => {
+                        reader.push(format!(" ** READ ERROR: {e} ** "));
+                        return;
+                    }
+                };
+                let done = match compression {
+                    Some(compression) => match compression.decoder(file.reader) {
+                        Ok(mut decoder) => reader.read_available(&mut decoder),
+                        Err(e) => {
+                            reader.push(format!(" ** READ ERROR: {e} ** "));
+                            false
+                        }
+                    },
+                    // only the current generation is still being written to
+                    None if follow && i == count - 1 => {
+                        reader.follow(file);
+                        return;
+                    }
+                    None => reader.read_available(&mut file.reader),
+                };
+                if !done {
+                    return;
+                }
+                reader.flush_partial();
             }
         }
     }
+    reader.end_of_input();
 }
 // pub fn reader_thread(app: Arc<App>) {
 //     let mut i: usize = 0;
@@ -81,3 +470,85 @@ pub fn reader_thread(app: Arc<SharedState>) {
 //         }
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logbuf::{Limits, LogBuf};
+
+    fn reader(events: channel::Sender<MergeEvent>) -> LineReader {
+        let (work_send, work_recv) = channel::unbounded();
+        let (match_send, match_recv) = channel::unbounded();
+        let app = Arc::new(SharedState::new(
+            vec![Source::Stdin],
+            LogBuf::new(Limits::default()),
+            work_send,
+            work_recv,
+            match_send,
+            match_recv,
+        ));
+        LineReader {
+            ingest: Ingest::new(app.clone()),
+            app,
+            source: 0,
+            partial: Vec::new(),
+            merge: Some(Merge {
+                events,
+                entry: None,
+                last_timestamp: 0,
+                idle: false,
+            }),
+        }
+    }
+
+    fn entries(events: &channel::Receiver<MergeEvent>) -> Vec<Entry> {
+        events
+            .try_iter()
+            .filter_map(|event| match event {
+                MergeEvent::Entry(_, entry) => Some(entry),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn text(first: &str, lines: usize) -> String {
+        let mut text = format!("{first}\n");
+        for i in 0..lines {
+            text.push_str(&format!("no timestamp {i}\n"));
+        }
+        text
+    }
+
+    #[test]
+    fn long_entries_are_sent_in_parts() {
+        let (events, receiver) = channel::unbounded();
+        let mut reader = reader(events);
+        let text = text("2023-10-11T13:14:56Z started", 2500);
+        let mut input: Box<dyn BufRead + Send> = Box::new(io::Cursor::new(text));
+        assert!(reader.read_available(&mut input));
+
+        let sent = entries(&receiver);
+        assert_eq!(sent.len(), 2);
+        assert!(sent.iter().all(|e| e.lines.len() == MAX_ENTRY_LINES));
+        assert!(sent.iter().all(|e| e.timestamp == 1697030096000));
+
+        drop(reader);
+        let rest = entries(&receiver);
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].lines.len(), 2501 - 2 * MAX_ENTRY_LINES);
+        assert_eq!(rest[0].timestamp, 1697030096000);
+    }
+
+    #[test]
+    fn entry_is_sent_when_no_input_is_buffered() {
+        let (events, receiver) = channel::unbounded();
+        let mut reader = reader(events);
+        let text = text("2023-10-11T13:14:56Z started", 10);
+        let mut input = BufReader::new(io::Cursor::new(text));
+        assert!(reader.read_available(&mut input));
+
+        let sent = entries(&receiver);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].lines.len(), 11);
+    }
+}
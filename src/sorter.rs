@@ -2,12 +2,12 @@ use std::sync::{atomic::Ordering, Arc};
 
 use crate::types::SharedState;
 
-// This threads inserts matches coming from worker threads into the matches vector
+// This threads merges matches coming from worker threads into the matches store
 pub fn sorter_thread(app: Arc<SharedState>) {
     let mut current_version = 0;
     loop {
         // matches will always be sorted by lineno
-        let matches = app.matches_channel_recv.recv().unwrap();
+        let mut matches = app.matches_channel_recv.recv().unwrap();
 
         // should not happen
         if matches.is_empty() {
@@ -17,7 +17,7 @@ pub fn sorter_thread(app: Arc<SharedState>) {
         let mut app_matches = app.matches.lock().unwrap();
 
         // during waiting for the lock, version might have changed
-        let actual = app.search_version.load(Ordering::Relaxed);
+        let actual = app.search_version.load(Ordering::SeqCst);
         if current_version < actual {
             current_version = actual;
         }
@@ -26,14 +26,11 @@ pub fn sorter_thread(app: Arc<SharedState>) {
             continue;
         }
 
-        app_matches.reserve(matches.len());
+        // matches of lines evicted meanwhile were already dropped
+        let first = app.logbuf.first();
+        matches.retain(|m| m.lineno >= first);
 
-        // TODO: optimize this naive implementation
-        for m in matches.iter() {
-            let pos = app_matches.binary_search_by_key(&m.lineno, |m| m.lineno);
-            if let Err(pos) = pos {
-                app_matches.insert(pos, m.clone());
-            }
-        }
+        app_matches.insert(matches);
+        app.request_redraw();
     }
 }
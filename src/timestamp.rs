@@ -0,0 +1,309 @@
+use std::{
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use regex::{Captures, Regex};
+
+// timestamps are only looked for at the start of a line
+const SEARCH_PREFIX: usize = 64;
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+struct Patterns {
+    // 1697030096, 1697030096123, 1697030096.123
+    epoch: Regex,
+    // Oct 11 13:14:56, optionally prefixed with <priority>
+    syslog: Regex,
+    // [Wed Oct 11 13:14:56.123456 2023] as in apache error logs
+    ctime: Regex,
+    // 2023-10-11T13:14:56.123+02:00, 2023-10-11 13:14:56,123, 2023/10/11 13:14:56 as in nginx error logs
+    iso: Regex,
+    // 11/Oct/2023:13:14:56 +0200 as used by apache and nginx
+    clf: Regex,
+}
+
+fn patterns() -> &'static Patterns {
+    static PATTERNS: OnceLock<Patterns> = OnceLock::new();
+    PATTERNS.get_or_init(|| Patterns {
+        epoch: Regex::new(r"^\[?(\d{13}|\d{10})(?:\.(\d{1,9}))?\b").unwrap(),
+        syslog: Regex::new(r"^(?:<\d{1,3}>)?([A-Z][a-z]{2}) {1,2}(\d{1,2}) (\d{2}):(\d{2}):(\d{2})\b")
+            .unwrap(),
+        ctime: Regex::new(
+            r"^\[?[A-Z][a-z]{2} ([A-Z][a-z]{2}) {1,2}(\d{1,2}) (\d{2}):(\d{2}):(\d{2})(?:\.(\d{1,9}))? (\d{4})\b",
+        )
+        .unwrap(),
+        iso: Regex::new(
+            r"(\d{4})[-/](\d{2})[-/](\d{2})[T ](\d{2}):(\d{2}):(\d{2})(?:[.,](\d{1,9}))? ?(Z|[+-]\d{2}:?\d{2})?",
+        )
+        .unwrap(),
+        clf: Regex::new(r"(\d{2})/([A-Z][a-z]{2})/(\d{4}):(\d{2}):(\d{2}):(\d{2})(?: ([+-]\d{4}))?")
+            .unwrap(),
+    })
+}
+
+struct DateTime {
+    year: i64,
+    month: i64,
+    day: i64,
+    hour: i64,
+    minute: i64,
+    second: i64,
+    millis: i64,
+    // seconds east of UTC
+    offset: i64,
+}
+
+impl DateTime {
+    fn to_millis(&self) -> Option<i64> {
+        if !(1..=12).contains(&self.month)
+            || !(1..=31).contains(&self.day)
+            || self.hour > 23
+            || self.minute > 59
+            || self.second > 60
+        {
+            return None;
+        }
+        let days = days_from_civil(self.year, self.month, self.day);
+        let seconds =
+            days * 86400 + self.hour * 3600 + self.minute * 60 + self.second - self.offset;
+        Some(seconds * 1000 + self.millis)
+    }
+}
+
+fn num(c: &Captures, i: usize) -> Option<i64> {
+    c.get(i)?.as_str().parse().ok()
+}
+
+fn group<'a>(c: &Captures<'a>, i: usize) -> Option<&'a str> {
+    c.get(i).map(|m| m.as_str())
+}
+
+// Finds a timestamp at the start of the line, returns milliseconds since epoch.
+// Timestamps without a timezone are taken as UTC.
+pub fn parse(line: &str) -> Option<i64> {
+    let end = line
+        .char_indices()
+        .nth(SEARCH_PREFIX)
+        .map_or(line.len(), |(i, _)| i);
+    let prefix = &line[..end];
+    let p = patterns();
+
+    if let Some(c) = p.epoch.captures(prefix) {
+        let value = num(&c, 1)?;
+        if c[1].len() == 13 {
+            return Some(value);
+        }
+        return Some(value * 1000 + millis(group(&c, 2)));
+    }
+
+    let datetime = if let Some(c) = p.syslog.captures(prefix) {
+        DateTime {
+            // syslog has no year, assume the current one
+            year: current_year(),
+            month: month(&c[1])?,
+            day: num(&c, 2)?,
+            hour: num(&c, 3)?,
+            minute: num(&c, 4)?,
+            second: num(&c, 5)?,
+            millis: 0,
+            offset: 0,
+        }
+    } else if let Some(c) = p.ctime.captures(prefix) {
+        DateTime {
+            year: num(&c, 7)?,
+            month: month(&c[1])?,
+            day: num(&c, 2)?,
+            hour: num(&c, 3)?,
+            minute: num(&c, 4)?,
+            second: num(&c, 5)?,
+            millis: millis(group(&c, 6)),
+            offset: 0,
+        }
+    } else if let Some(c) = p.iso.captures(prefix) {
+        DateTime {
+            year: num(&c, 1)?,
+            month: num(&c, 2)?,
+            day: num(&c, 3)?,
+            hour: num(&c, 4)?,
+            minute: num(&c, 5)?,
+            second: num(&c, 6)?,
+            millis: millis(group(&c, 7)),
+            offset: offset_seconds(group(&c, 8))?,
+        }
+    } else if let Some(c) = p.clf.captures(prefix) {
+        DateTime {
+            year: num(&c, 3)?,
+            month: month(&c[2])?,
+            day: num(&c, 1)?,
+            hour: num(&c, 4)?,
+            minute: num(&c, 5)?,
+            second: num(&c, 6)?,
+            millis: 0,
+            offset: offset_seconds(group(&c, 7))?,
+        }
+    } else {
+        return None;
+    };
+    datetime.to_millis()
+}
+
+fn month(name: &str) -> Option<i64> {
+    MONTHS.iter().position(|m| *m == name).map(|i| i as i64 + 1)
+}
+
+// first three digits of a fraction of a second
+fn millis(fraction: Option<&str>) -> i64 {
+    let Some(fraction) = fraction else {
+        return 0;
+    };
+    let digits: String = fraction.chars().chain("000".chars()).take(3).collect();
+    digits.parse().unwrap_or(0)
+}
+
+// Z, +02:00, -0700
+fn offset_seconds(offset: Option<&str>) -> Option<i64> {
+    let Some(offset) = offset else {
+        return Some(0);
+    };
+    if offset == "Z" {
+        return Some(0);
+    }
+    let sign = if offset.starts_with('-') { -1 } else { 1 };
+    let digits: String = offset[1..].chars().filter(|c| *c != ':').collect();
+    let hours: i64 = digits[..2].parse().ok()?;
+    let minutes: i64 = digits[2..].parse().ok()?;
+    Some(sign * (hours * 3600 + minutes * 60))
+}
+
+// days since 1970-01-01 of a proleptic gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn current_year() -> i64 {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64 / 86400);
+    // inverse of days_from_civil, only the year is needed
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let year = year_of_era + era * 400;
+    if month_index >= 10 {
+        year + 1
+    } else {
+        year
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2023-10-11T13:14:56Z
+    const SECONDS: i64 = 1697030096;
+
+    #[test]
+    fn iso() {
+        let ms = SECONDS * 1000;
+        assert_eq!(parse("2023-10-11T13:14:56Z started"), Some(ms));
+        assert_eq!(parse("2023-10-11 13:14:56 started"), Some(ms));
+        assert_eq!(parse("2023-10-11T13:14:56.123Z"), Some(ms + 123));
+        assert_eq!(parse("2023-10-11 13:14:56,5 x"), Some(ms + 500));
+        assert_eq!(parse("2023-10-11T13:14:56.123456789Z"), Some(ms + 123));
+        assert_eq!(parse("2023-10-11T15:14:56+02:00"), Some(ms));
+        assert_eq!(parse("2023-10-11T06:14:56 -0700"), Some(ms));
+    }
+
+    #[test]
+    fn nginx_error_log() {
+        let line = "2023/10/11 13:14:56 [error] 1234#0: *1 open() failed";
+        assert_eq!(parse(line), Some(SECONDS * 1000));
+    }
+
+    #[test]
+    fn apache_error_log() {
+        let line = "[Wed Oct 11 13:14:56.123456 2023] [core:error] [pid 1234] AH00037";
+        assert_eq!(parse(line), Some(SECONDS * 1000 + 123));
+        assert_eq!(parse("Wed Oct 11 13:14:56 2023 x"), Some(SECONDS * 1000));
+        assert_eq!(parse("[Wed Oct 11 13:14:56 2023] x"), Some(SECONDS * 1000));
+    }
+
+    #[test]
+    fn iso_is_found_within_the_prefix() {
+        let ms = SECONDS * 1000;
+        assert_eq!(parse("INFO [main] 2023-10-11 13:14:56 up"), Some(ms));
+        let late = format!("{} 2023-10-11 13:14:56", "x".repeat(SEARCH_PREFIX));
+        assert_eq!(parse(&late), None);
+        // the prefix is counted in characters
+        let wide = format!("{} 2023-10-11 13:14:56", "é".repeat(40));
+        assert_eq!(parse(&wide), Some(ms));
+    }
+
+    #[test]
+    fn epoch() {
+        assert_eq!(parse("1697030096 started"), Some(SECONDS * 1000));
+        assert_eq!(parse("1697030096123 started"), Some(SECONDS * 1000 + 123));
+        assert_eq!(parse("1697030096.25 started"), Some(SECONDS * 1000 + 250));
+        assert_eq!(parse("[1697030096] started"), Some(SECONDS * 1000));
+        // only seconds or milliseconds
+        assert_eq!(parse("16970300961 started"), None);
+        assert_eq!(parse("169703009612 started"), None);
+        assert_eq!(parse("status 1697030096"), None);
+    }
+
+    #[test]
+    fn syslog_is_in_the_current_year() {
+        let year = current_year();
+        let ms = days_from_civil(year, 10, 1) * 86400 * 1000 + (13 * 3600 + 14 * 60 + 56) * 1000;
+        assert_eq!(parse("Oct  1 13:14:56 host sshd[1]: x"), Some(ms));
+        assert_eq!(parse("<34>Oct  1 13:14:56 host su: x"), Some(ms));
+        assert_eq!(parse("Foo  1 13:14:56 host"), None);
+    }
+
+    #[test]
+    fn current_year_contains_today() {
+        let today = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+            / 86400;
+        let year = current_year();
+        assert!(days_from_civil(year, 1, 1) <= today);
+        assert!(today < days_from_civil(year + 1, 1, 1));
+    }
+
+    #[test]
+    fn clf() {
+        let line = r#"127.0.0.1 - - [11/Oct/2023:15:14:56 +0200] "GET / HTTP/1.1" 200"#;
+        assert_eq!(parse(line), Some(SECONDS * 1000));
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("no timestamp here"), None);
+        assert_eq!(parse("2023-13-11T13:14:56Z"), None);
+        assert_eq!(parse("2023-10-11T24:14:56Z"), None);
+    }
+
+    #[test]
+    fn civil_days() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+    }
+}
@@ -1,15 +1,31 @@
-use std::sync::{
-    atomic::{AtomicBool, AtomicUsize},
-    Mutex, RwLock,
+use std::{
+    fmt,
+    ops::Range,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize},
+        Mutex, RwLock,
+    },
+    time::Instant,
 };
 
 use crossbeam::channel;
 use regex::Regex;
 
+use crate::control::KeySequence;
+use crate::coverage::Coverage;
 use crate::logbuf::LogBuf;
+use crate::matches::Matches;
+use crate::query::{Query, SearchModes};
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+// sources are toggled with bits of a u64
+pub const MAX_SOURCES: usize = 64;
+
+// index into SharedState::sources
+pub type SourceId = u8;
+
 #[derive(Default)]
 pub struct Point {
     pub x: usize,
@@ -20,11 +36,62 @@ pub struct Point {
 pub enum Panel {
     Search,
     Matches,
+    Log,
+}
+
+// Where log lines are read from
+#[derive(Clone)]
+pub enum Source {
+    Stdin,
+    // a log file and its rotated generations, read oldest first
+    Files { name: PathBuf, paths: Vec<PathBuf> },
+}
+
+impl Source {
+    // name without directories, used to tag lines
+    pub fn short_name(&self) -> String {
+        match self {
+            Source::Stdin => String::from("stdin"),
+            Source::Files { name, .. } => name
+                .file_name()
+                .unwrap_or(name.as_os_str())
+                .to_string_lossy()
+                .into_owned(),
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Stdin => write!(f, "stdin"),
+            Source::Files { paths, .. } if paths.len() == 1 => {
+                write!(f, "{}", paths[0].display())
+            }
+            Source::Files { name, paths } => {
+                write!(f, "{} (+{} rotated)", name.display(), paths.len() - 1)
+            }
+        }
+    }
+}
+
+// about how much text is searched at once, before matches and progress are reported
+pub const CHUNK_BYTES: usize = 64 * 1024;
+
+// Lines for workers to search
+#[derive(Clone)]
+pub struct WorkItem {
+    pub lines: Range<usize>,
+    // search_version the lines are searched with, stale items are skipped
+    pub version: usize,
+    // new lines of the log rather than a part of restarted search
+    pub ingested: bool,
 }
 
 #[derive(Clone)]
 pub struct Match {
     pub lineno: usize,
+    pub source: SourceId,
     pub version: usize,
 }
 
@@ -32,54 +99,118 @@ pub struct Match {
 pub struct SharedState {
     pub should_quit: AtomicBool,
 
+    pub sources: Vec<Source>,
     pub logbuf: LogBuf,
 
     pub search: RwLock<SearchCriteria>,
     pub search_version: AtomicUsize,
+    // lines searched so far with the current search
+    pub coverage: Mutex<Coverage>,
 
-    // vec of line numbers
-    pub matches: Mutex<Vec<Match>>,
+    pub matches: Mutex<Matches>,
+    // matches of evicted lines removed so far, changed with matches locked
+    pub matches_dropped: AtomicUsize,
 
-    pub regex_channel: channel::Sender<(usize, usize)>,
+    pub regex_channel: channel::Sender<WorkItem>,
+    // only used to discard queued work
+    pub regex_channel_recv: channel::Receiver<WorkItem>,
     pub matches_channel_send: channel::Sender<Vec<Match>>,
     pub matches_channel_recv: channel::Receiver<Vec<Match>>,
+
+    // wakes the UI to redraw, holds at most one request
+    redraw_send: channel::Sender<()>,
+    pub redraw_recv: channel::Receiver<()>,
 }
 
 impl SharedState {
     pub fn new(
-        regex_channel: channel::Sender<(usize, usize)>,
+        sources: Vec<Source>,
+        logbuf: LogBuf,
+        regex_channel: channel::Sender<WorkItem>,
+        regex_channel_recv: channel::Receiver<WorkItem>,
         matches_channel_send: channel::Sender<Vec<Match>>,
         matches_channel_recv: channel::Receiver<Vec<Match>>,
     ) -> Self {
+        let (redraw_send, redraw_recv) = channel::bounded(1);
         SharedState {
             should_quit: AtomicBool::new(false),
 
-            logbuf: LogBuf::new(),
+            sources,
+            logbuf,
 
-            search: RwLock::new(SearchCriteria { re: None }),
+            search: RwLock::new(SearchCriteria {
+                query: None,
+                filters: Vec::new(),
+                hidden_sources: 0,
+            }),
             search_version: AtomicUsize::new(0),
+            coverage: Mutex::new(Coverage::default()),
 
-            matches: Mutex::new(Vec::new()),
+            matches: Mutex::new(Matches::default()),
+            matches_dropped: AtomicUsize::new(0),
 
             regex_channel,
+            regex_channel_recv,
             matches_channel_send,
             matches_channel_recv,
+
+            redraw_send,
+            redraw_recv,
         }
     }
+
+    // asks the UI to redraw, requests made before it redraws are merged
+    pub fn request_redraw(&self) {
+        let _ = self.redraw_send.try_send(());
+    }
 }
 
 // Owned by the UI thread and not shared
 pub struct UIState {
     pub log_offset: Point,
-    pub log_max_width: usize,
+    // lines fitting in the log panel
+    pub log_height: usize,
+    // columns of the log panel left for the text
+    pub log_width: usize,
+    // line to center in the log panel when drawn next
+    pub log_locate: Option<usize>,
+    // line selected in the log panel when it has focus
+    pub log_cursor: usize,
+    // line numbers are shown in the log and matches panels
+    pub line_numbers: bool,
 
     pub selected_panel: Panel,
+    // typed `:` command, keys go to it while set
+    pub command: Option<String>,
+    // keys typed so far of a motion like 10j or gg
+    pub keys: KeySequence,
 
     pub matches_selected: Option<usize>,
     pub matches_should_locate: bool,
     pub matches_offset: Point,
+    // matches fitting in the matches panel
+    pub matches_height: usize,
+    // value of SharedState::matches_dropped the selection is relative to
+    pub matches_dropped: usize,
 
     pub search_query: String,
+    // why the search query could not be parsed
+    pub search_error: Option<String>,
+    pub search_modes: SearchModes,
+    // typed query is searched for once this passes
+    pub search_due: Option<Instant>,
+    // when the running search started, None if not searching
+    pub search_started: Option<Instant>,
+    // queued work of the search was discarded
+    pub search_cancelled: bool,
+    pub filters: Vec<Filter>,
+    // log panel shows only the matched lines
+    pub filtered_view: bool,
+    // lines shown before and after each match in the filtered view
+    pub context_lines: usize,
+
+    // bit set of sources not shown in log and matches
+    pub hidden_sources: u64,
 
     pub following: bool,
 }
@@ -88,15 +219,33 @@ impl Default for UIState {
     fn default() -> Self {
         UIState {
             log_offset: Point::default(),
-            log_max_width: 0,
+            log_height: 0,
+            log_width: 0,
+            log_locate: None,
+            log_cursor: 0,
+            line_numbers: false,
 
             selected_panel: Panel::Search,
+            command: None,
+            keys: KeySequence::default(),
 
             matches_selected: None,
             matches_should_locate: false,
             matches_offset: Point::default(),
+            matches_height: 0,
+            matches_dropped: 0,
 
             search_query: String::new(),
+            search_error: None,
+            search_modes: SearchModes::default(),
+            search_due: None,
+            search_started: None,
+            search_cancelled: false,
+            filters: Vec::new(),
+            filtered_view: false,
+            context_lines: 0,
+
+            hidden_sources: 0,
 
             following: true,
         }
@@ -107,5 +256,39 @@ impl Default for UIState {
 // only one thread is writing to this
 #[derive(Clone)]
 pub struct SearchCriteria {
-    pub re: Option<Regex>,
+    pub query: Option<Query>,
+    // enabled filters, applied before the query
+    pub filters: Vec<Filter>,
+    pub hidden_sources: u64,
+}
+
+// A query pushed on the filter stack, lines have to match it or not match it if excluding
+#[derive(Clone)]
+pub struct Filter {
+    pub text: String,
+    pub query: Query,
+    pub exclude: bool,
+    pub enabled: bool,
+}
+
+impl SearchCriteria {
+    pub fn is_active(&self) -> bool {
+        self.query.is_some() || !self.filters.is_empty()
+    }
+
+    // line survives the filters and matches the query if there is one
+    pub fn is_match(&self, line: &str) -> bool {
+        self.filters
+            .iter()
+            .all(|f| f.query.is_match(line) != f.exclude)
+            && self.query.as_ref().is_none_or(|q| q.is_match(line))
+    }
+
+    pub fn highlight(&self) -> Option<Regex> {
+        self.query.as_ref()?.highlight().cloned()
+    }
+
+    pub fn is_source_hidden(&self, source: SourceId) -> bool {
+        self.hidden_sources & (1 << source) != 0
+    }
 }
@@ -1,47 +1,90 @@
 use std::cmp::min;
+use std::sync::atomic::Ordering;
 
 use ratatui::widgets::block::Title;
 use ratatui::Frame;
 use ratatui::{prelude::*, widgets::*};
 use regex::Regex;
 
-use crate::types::{Panel, Point, SharedState, UIState, VERSION};
+use crate::logbuf::LogReader;
+use crate::matches::Matches;
+use crate::types::{Panel, Point, SharedState, SourceId, UIState, VERSION};
+use crate::view::{LogView, Row};
+
+const SOURCE_COLORS: [Color; 6] = [
+    Color::Cyan,
+    Color::Green,
+    Color::Yellow,
+    Color::Magenta,
+    Color::Blue,
+    Color::LightRed,
+];
+const SOURCE_TAG_MAX_WIDTH: usize = 12;
+const PROGRESS_WIDTH: usize = 10;
+
+// byte index of the character at column, the end of the line if it is shorter
+fn byte_index(line: &str, column: usize) -> usize {
+    line.char_indices()
+        .nth(column)
+        .map_or(line.len(), |(i, _)| i)
+}
 
-// TODO: refactor into 1 function somehow ? (unsure about lifetimes w/ generics)
-fn cut_text_window<'a>(source: &'a Vec<String>, rect: &Rect, offset: &Point) -> Vec<&'a str> {
+// horizontal offsets and widths count characters, so multibyte ones are never cut
+fn cut_text_window<'a>(source: Vec<&'a str>, rect: &Rect, offset: &Point) -> Vec<&'a str> {
     let mut text_lines: Vec<&str> = Vec::with_capacity(rect.height as usize);
 
     let available_lines = min(rect.height as usize, source.len().saturating_sub(offset.y));
 
     for line in source.iter().skip(offset.y).take(available_lines) {
-        let available_width = min(rect.width as usize, line.len().saturating_sub(offset.x));
-
-        if available_width != 0 {
-            text_lines.push(&line[offset.x..offset.x + available_width]);
-        } else {
-            text_lines.push("");
-        }
+        let rest = &line[byte_index(line, offset.x)..];
+        text_lines.push(&rest[..byte_index(rest, rect.width as usize)]);
     }
 
     text_lines
 }
 
-fn cut_text_window2<'a>(source: Vec<&'a str>, rect: &Rect, offset: &Point) -> Vec<&'a str> {
-    let mut text_lines: Vec<&str> = Vec::with_capacity(rect.height as usize);
+// width of the source tag in front of every line, 0 when there is only one source
+fn source_gutter_width(app: &SharedState) -> u16 {
+    if app.sources.len() < 2 {
+        return 0;
+    }
+    let longest = app
+        .sources
+        .iter()
+        .map(|s| s.short_name().len())
+        .max()
+        .unwrap_or(0);
+    // separating space
+    min(longest, SOURCE_TAG_MAX_WIDTH) as u16 + 1
+}
 
-    let available_lines = min(rect.height as usize, source.len().saturating_sub(offset.y));
+// width of the line number gutter, fits the last line number and a space
+fn line_number_width(len: usize) -> u16 {
+    len.max(1).ilog10() as u16 + 2
+}
 
-    for line in source.iter().skip(offset.y).take(available_lines) {
-        let available_width = min(rect.width as usize, line.len().saturating_sub(offset.x));
+// line numbers start at 1
+fn line_number(lineno: usize, width: u16) -> Span<'static> {
+    Span::styled(
+        format!("{:>width$} ", lineno + 1, width = width as usize - 1),
+        Style::default().fg(Color::DarkGray),
+    )
+}
 
-        if available_width != 0 {
-            text_lines.push(&line[offset.x..offset.x + available_width]);
-        } else {
-            text_lines.push("");
-        }
-    }
+fn source_color(source: SourceId) -> Color {
+    SOURCE_COLORS[source as usize % SOURCE_COLORS.len()]
+}
 
-    text_lines
+fn source_tag(app: &SharedState, source: SourceId, width: u16) -> Span<'static> {
+    let name = app.sources[source as usize].short_name();
+    let name: String = name
+        .chars()
+        .take(width.saturating_sub(1) as usize)
+        .collect();
+    Span::styled(
+        format!("{:<width$}", name, width = width as usize),
+        Style::default().fg(source_color(source)),
+    )
 }
 
 fn color_line<'a>(re: &Option<Regex>, line: &'a str, highlight: bool, width: u16) -> Line<'a> {
@@ -101,70 +144,142 @@ fn color_line<'a>(re: &Option<Regex>, line: &'a str, highlight: bool, width: u16
 
     Line::from(result)
 }
-fn ensure_log_in_viewport(app: &SharedState, ui: &mut UIState, rect: Rect) {
-    let matches = app.matches.lock().unwrap();
-    let log_lines = app.logbuf.tmp_read();
-    if ui.matches_should_locate && ui.matches_selected.is_some() {
-        let match_i = matches[ui.matches_selected.unwrap()].lineno;
-
-        if match_i < ((rect.height as usize) / 2) {
-            ui.log_offset.y = 0;
-        } else if match_i >= log_lines.len() - rect.height as usize / 2 {
-            // TODO: sus -1 here
-            ui.log_offset.y = log_lines.len() - (rect.height as usize - 1);
-        } else {
-            ui.log_offset.y = match_i - rect.height as usize / 2;
-        }
+// matches of evicted lines are removed from the front, keeps the selection on the same match
+fn follow_dropped_matches(app: &SharedState, ui: &mut UIState) {
+    let _matches = app.matches.lock().unwrap();
+    let dropped = app.matches_dropped.load(Ordering::Relaxed);
+    let count = dropped - ui.matches_dropped;
+    ui.matches_dropped = dropped;
+
+    ui.matches_offset.y = ui.matches_offset.y.saturating_sub(count);
+    ui.matches_selected = ui.matches_selected.and_then(|i| i.checked_sub(count));
+}
+
+fn ensure_log_in_viewport(ui: &mut UIState, view: &LogView, matches: &Matches, rect: Rect) {
+    let height = rect.height as usize;
+    // center the line, unless it is close to the end
+    let center = |lineno| {
+        min(
+            view.step(lineno, -(height as isize / 2)),
+            view.last_page(height),
+        )
+    };
 
+    let selected = ui.matches_selected.and_then(|i| matches.get(i));
+    if let (true, Some(selected)) = (ui.matches_should_locate, selected) {
+        ui.log_offset.y = center(selected.lineno);
         ui.matches_should_locate = false;
     }
+    if let Some(lineno) = ui.log_locate.take() {
+        ui.log_offset.y = center(lineno);
+    }
     if ui.following {
         // TODO: probably not a place for it
         ui.matches_selected = None;
-        ui.log_offset.y = log_lines.len().saturating_sub(rect.height as usize);
+        ui.log_offset.y = view.last_page(height);
+        ui.log_cursor = view.prev(view.len().saturating_sub(1)).unwrap_or(0);
     }
 }
 
 fn render_log_text<'a>(
     app: &SharedState,
     ui: &mut UIState,
-    log_lines: &'a Vec<String>,
+    log_lines: &'a LogReader<'a>,
     rect: Rect,
 ) -> Text<'a> {
-    ensure_log_in_viewport(app, ui, rect);
-
     let matches = app.matches.lock().unwrap();
-    // let log_lines = &app.log_lines.read().unwrap();
-    let re = app.search.read().unwrap().re.clone();
+    let (re, searching) = {
+        let search = app.search.read().unwrap();
+        (search.highlight(), search.is_active())
+    };
+
+    ui.log_height = rect.height as usize;
+    let view = LogView::of_panel(log_lines, ui, searching, &matches);
+    ensure_log_in_viewport(ui, &view, &matches, rect);
 
-    let text_lines = cut_text_window(log_lines, &rect, &ui.log_offset);
+    let gutter = source_gutter_width(app);
+    // real line numbers are always shown in the filtered view
+    let number_gutter = if ui.line_numbers || (ui.filtered_view && searching) {
+        line_number_width(log_lines.len())
+    } else {
+        0
+    };
+    let text_rect = Rect {
+        width: rect.width.saturating_sub(gutter + number_gutter),
+        ..rect
+    };
+    ui.log_width = text_rect.width as usize;
+
+    let rows = view.window(ui.log_offset.y, rect.height as usize);
+    let text_lines = cut_text_window(
+        rows.iter()
+            .map(|row| match row {
+                Row::Line(i) => log_lines.line(*i),
+                Row::Separator => "",
+            })
+            .collect(),
+        &text_rect,
+        &Point {
+            x: ui.log_offset.x,
+            y: 0,
+        },
+    );
 
     let mut colored_lines: Vec<Line> = Vec::with_capacity(rect.height as usize);
 
-    for (i, line) in text_lines.iter().enumerate() {
-        let highlight = if let Some(match_i) = ui.matches_selected {
-            matches[match_i].lineno == ui.log_offset.y + i
-        } else {
-            false
+    for (line, row) in text_lines.iter().zip(rows.iter()) {
+        let Row::Line(lineno) = *row else {
+            colored_lines.push(Line::styled("--", Style::default().fg(Color::DarkGray)));
+            continue;
+        };
+        // the cursor while the log has focus, the selected match otherwise
+        let highlight = match ui.selected_panel {
+            Panel::Log => ui.log_cursor == lineno,
+            _ => ui
+                .matches_selected
+                .and_then(|i| matches.get(i))
+                .is_some_and(|m| m.lineno == lineno),
         };
-        colored_lines.push(color_line(&re, line, highlight, rect.width));
+        let mut colored = color_line(&re, line, highlight, text_rect.width);
+        if number_gutter != 0 {
+            colored.spans.insert(0, line_number(lineno, number_gutter));
+        }
+        if gutter != 0 {
+            colored
+                .spans
+                .insert(0, source_tag(app, log_lines.source(lineno), gutter));
+        }
+        colored_lines.push(colored);
     }
 
     Text::from(colored_lines)
 }
 
-fn ensure_matches_in_viewport(app: &SharedState, ui: &mut UIState, rect: Rect) {
+// Matches of lines in the log snapshot of this frame. Workers search lines as soon as they
+// are published, so matches of lines written after the snapshot was taken are already there.
+fn shown_matches(matches: &Matches, log_lines: &LogReader) -> usize {
+    matches.partition_point(|m| m.lineno < log_lines.len())
+}
+
+fn ensure_matches_in_viewport(
+    app: &SharedState,
+    ui: &mut UIState,
+    log_lines: &LogReader,
+    rect: Rect,
+) {
     if ui.matches_selected.is_none() {
         if ui.following {
             // TODO: probably not a place for it
             let matches = app.matches.lock().unwrap();
-            ui.matches_offset.y = matches.len().saturating_sub(rect.height as usize);
+            let len = shown_matches(&matches, log_lines);
+            ui.matches_offset.y = len.saturating_sub(rect.height as usize);
         }
         return;
     }
     let selected = ui.matches_selected.unwrap();
     {
         let matches = app.matches.lock().unwrap();
+        let len = shown_matches(&matches, log_lines);
 
         if selected < ui.matches_offset.y {
             ui.matches_offset.y = selected;
@@ -175,9 +290,9 @@ fn ensure_matches_in_viewport(app: &SharedState, ui: &mut UIState, rect: Rect) {
 
         if selected < ((rect.height as usize) / 2) {
             ui.matches_offset.y = 0;
-        } else if selected >= matches.len() - rect.height as usize / 2 {
+        } else if selected >= len.saturating_sub(rect.height as usize / 2) {
             // TODO: sus -1 here, should be rewritten
-            ui.matches_offset.y = matches.len().saturating_sub(rect.height as usize - 1);
+            ui.matches_offset.y = len.saturating_sub(rect.height as usize - 1);
         } else {
             ui.matches_offset.y = selected - rect.height as usize / 2;
         }
@@ -187,35 +302,190 @@ fn ensure_matches_in_viewport(app: &SharedState, ui: &mut UIState, rect: Rect) {
 fn render_matches_text<'a>(
     app: &SharedState,
     ui: &mut UIState,
-    log_lines: &'a [String],
+    log_lines: &'a LogReader<'a>,
     rect: Rect,
 ) -> Text<'a> {
     // TODO: this whole fn probably should be refactored
-    ensure_matches_in_viewport(app, ui, rect);
+    ui.matches_height = rect.height as usize;
+    ensure_matches_in_viewport(app, ui, log_lines, rect);
 
-    // let log_lines = &app.log_lines.read().unwrap();
-    let matches: Vec<_> = app
-        .matches
-        .lock()
-        .unwrap()
+    let gutter = source_gutter_width(app);
+    let number_gutter = if ui.line_numbers {
+        line_number_width(log_lines.len())
+    } else {
+        0
+    };
+    let text_rect = Rect {
+        width: rect.width.saturating_sub(gutter + number_gutter),
+        ..rect
+    };
+
+    // only the shown matches are looked at
+    let top = ui.matches_offset.y;
+    let shown: Vec<_> = {
+        let matches = app.matches.lock().unwrap();
+        let end = (top + rect.height as usize).min(shown_matches(&matches, log_lines));
+        matches
+            .range(top..end.max(top))
+            .map(|m| (m.lineno, m.source))
+            .collect()
+    };
+    let matches = shown
         .iter()
-        .map(|i| &log_lines[(*i).lineno][..])
+        .map(|(lineno, _)| log_lines.line(*lineno))
         .collect();
-    let text_lines = cut_text_window2(matches, &rect, &ui.matches_offset);
+    let text_lines = cut_text_window(
+        matches,
+        &text_rect,
+        &Point {
+            x: ui.matches_offset.x,
+            y: 0,
+        },
+    );
 
     let mut colored_lines: Vec<Line> = Vec::with_capacity(rect.height as usize);
 
-    let re = app.search.read().unwrap().re.clone();
+    let re = app.search.read().unwrap().highlight();
     for (i, line) in text_lines.iter().enumerate() {
-        let highlight = (ui.selected_panel == Panel::Matches)
-            && (ui.matches_selected == Some(i + ui.matches_offset.y));
-        colored_lines.push(color_line(&re, line, highlight, rect.width));
+        let highlight =
+            (ui.selected_panel == Panel::Matches) && (ui.matches_selected == Some(top + i));
+        let mut colored = color_line(&re, line, highlight, text_rect.width);
+        let (lineno, source) = shown[i];
+        if number_gutter != 0 {
+            colored.spans.insert(0, line_number(lineno, number_gutter));
+        }
+        if gutter != 0 {
+            colored.spans.insert(0, source_tag(app, source, gutter));
+        }
+        colored_lines.push(colored);
     }
 
     Text::from(colored_lines)
 }
 
+// every row stands for an equal part of the log, shaded by how much of it was searched
+fn render_coverage(app: &SharedState, log_lines: &LogReader, rect: Rect) -> Text<'static> {
+    let coverage = app.coverage.lock().unwrap();
+    let (first, len) = (log_lines.first(), log_lines.len());
+    let height = rect.height as usize;
+    let style = Style::default().fg(Color::DarkGray);
+
+    let rows: Vec<Line> = (0..height)
+        .map(|row| {
+            let start = first + (len - first) * row / height;
+            let end = first + (len - first) * (row + 1) / height;
+            let symbol = match coverage.covered(start..end) {
+                _ if start == end => " ",
+                covered if covered == end - start => "█",
+                0 => "░",
+                _ => "▒",
+            };
+            Line::styled(symbol, style)
+        })
+        .collect();
+    Text::from(rows)
+}
+
+fn format_duration(seconds: u64) -> String {
+    if seconds < 60 {
+        format!("{seconds}s")
+    } else {
+        format!("{}m{:02}s", seconds / 60, seconds % 60)
+    }
+}
+
+// match count and, while searching, progress with an estimate of the time left
+fn render_matches_title(app: &SharedState, ui: &UIState, log_lines: &LogReader) -> String {
+    let count = app.matches.lock().unwrap().len();
+    let mut title = format!(" Matches {count} ");
+    let Some(started) = ui.search_started else {
+        return title;
+    };
+
+    // progress is measured against the lines there were when the search started
+    let coverage = app.coverage.lock().unwrap();
+    let first = log_lines.first();
+    let end = coverage.end().min(log_lines.len());
+    let total = end.saturating_sub(first);
+    let searched = coverage.covered(first..end.max(first));
+    drop(coverage);
+    if searched >= total {
+        return title;
+    }
+
+    let percent = searched * 100 / total;
+    if ui.search_cancelled {
+        title += &format!("· cancelled at {percent}% ");
+        return title;
+    }
+    let filled = searched * PROGRESS_WIDTH / total;
+    title += &format!(
+        "· {percent}% {}{} ",
+        "█".repeat(filled),
+        "░".repeat(PROGRESS_WIDTH - filled)
+    );
+    // the rate is not known well enough right after starting
+    let elapsed = started.elapsed();
+    if elapsed.as_secs() >= 1 && searched > 0 {
+        let left = elapsed.as_secs_f64() * (total - searched) as f64 / searched as f64;
+        title += &format!("ETA {} ", format_duration(left.round() as u64));
+    }
+    title
+}
+
+// source names, colored and marked when hidden if there are more of them
+fn render_log_title(app: &SharedState, ui: &UIState) -> Line<'static> {
+    let name = match (ui.filtered_view, ui.context_lines) {
+        (false, _) => String::from(" Log {"),
+        (true, 0) => String::from(" Log (filtered) {"),
+        (true, context) => format!(" Log (filtered, context {context}) {{"),
+    };
+    if app.sources.len() < 2 {
+        return Line::raw(format!("{name}{}}} ", app.sources[0]));
+    }
+
+    let mut spans = vec![Span::raw(name)];
+    for (i, source) in app.sources.iter().enumerate() {
+        if i != 0 {
+            spans.push(Span::raw(", "));
+        }
+        let style = if ui.hidden_sources & (1 << i) != 0 {
+            Style::default().fg(Color::DarkGray).crossed_out()
+        } else {
+            Style::default().fg(source_color(i as SourceId))
+        };
+        spans.push(Span::styled(source.to_string(), style));
+    }
+    spans.push(Span::raw("} "));
+    Line::from(spans)
+}
+
+// filter stack as chips, numbered for toggling with alt
+fn render_filters(ui: &UIState) -> Line<'static> {
+    let mut spans = Vec::new();
+    for (i, filter) in ui.filters.iter().enumerate() {
+        let (sign, color) = if filter.exclude {
+            ('-', Color::Red)
+        } else {
+            ('+', Color::Green)
+        };
+        let style = if filter.enabled {
+            Style::default().fg(Color::Black).bg(color)
+        } else {
+            Style::default().fg(Color::DarkGray).crossed_out()
+        };
+        spans.push(Span::styled(
+            format!(" {} {sign}{} ", i + 1, filter.text),
+            style,
+        ));
+        spans.push(Span::raw(" "));
+    }
+    Line::from(spans)
+}
+
 pub fn render_ui(app: &SharedState, ui: &mut UIState, frame: &mut Frame) {
+    follow_dropped_matches(app, ui);
+
     // default colors TODO: extract to some config
     let highlight_style = Style::default().bold().fg(Color::White);
 
@@ -226,44 +496,83 @@ pub fn render_ui(app: &SharedState, ui: &mut UIState, frame: &mut Frame) {
         .split(frame.size());
 
     // log window
-    let log_block = Block::default()
+    let mut log_block = Block::default()
         .borders(Borders::TOP)
-        .title(Title::from(" Log {stdin} ").alignment(Alignment::Center))
+        .title(Title::from(render_log_title(app, ui)).alignment(Alignment::Center))
         .title(Title::from(format!(" Apyr v{VERSION}")).alignment(Alignment::Right));
+    if ui.selected_panel == Panel::Log {
+        log_block = log_block.border_style(highlight_style);
+    }
+    let log_rect = log_block.inner(main_layout[0]);
+    frame.render_widget(log_block, main_layout[0]);
+    let log_lines = app.logbuf.read();
+    // searched parts of the log are shown in a strip on the right while searching
+    let log_rect = if app.search.read().unwrap().is_active() {
+        let [text_rect, strip_rect] = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Min(0), Constraint::Length(1)])
+            .split(log_rect)[..]
+        else {
+            unreachable!()
+        };
+        frame.render_widget(
+            Paragraph::new(render_coverage(app, &log_lines, strip_rect)),
+            strip_rect,
+        );
+        text_rect
+    } else {
+        log_rect
+    };
     frame.render_widget(
-        Paragraph::new(render_log_text(
-            app,
-            ui,
-            &app.logbuf.tmp_read(),
-            log_block.inner(main_layout[0]),
-        ))
-        .block(log_block),
-        main_layout[0],
+        Paragraph::new(render_log_text(app, ui, &log_lines, log_rect)),
+        log_rect,
     );
 
     // bottom cluster = search + matches
     let sub_layout = Layout::default()
         .direction(Direction::Vertical)
-        .constraints([Constraint::Length(2), Constraint::Min(3)])
+        .constraints([
+            Constraint::Length(if ui.filters.is_empty() { 2 } else { 3 }),
+            Constraint::Min(3),
+        ])
         .split(main_layout[1]);
+    let modes = ui.search_modes.names();
+    let mut search_title = String::from(" Search");
+    if !modes.is_empty() {
+        search_title += &format!(" ({})", modes.join(", "));
+    }
+    match &ui.search_error {
+        Some(error) => search_title += &format!(": {error} "),
+        None => search_title += "  ",
+    }
     let mut search_block = Block::default()
         .borders(Borders::TOP)
-        .title(Title::from(" Search  ").alignment(Alignment::Center));
-    if app.search.read().unwrap().re.is_none() {
+        .title(Title::from(search_title).alignment(Alignment::Center));
+    if !app.search.read().unwrap().is_active() {
         search_block = search_block.style(Style::default().fg(Color::Red));
     }
     if ui.selected_panel == Panel::Search {
         search_block = search_block.border_style(highlight_style);
     }
+    // a typed command is shown in place of the query
+    let input = match &ui.command {
+        Some(command) => format!(":{command}"),
+        None => ui.search_query.clone(),
+    };
     frame.render_widget(
-        Paragraph::new(ui.search_query.clone()).block(search_block),
+        Paragraph::new(if ui.filters.is_empty() {
+            Text::raw(input)
+        } else {
+            Text::from(vec![render_filters(ui), Line::raw(input)])
+        })
+        .block(search_block),
         sub_layout[0],
     );
 
     // matches
     let mut matches_block = Block::new()
         .borders(Borders::TOP)
-        .title(Title::from(" Matches ").alignment(Alignment::Center));
+        .title(Title::from(render_matches_title(app, ui, &log_lines)).alignment(Alignment::Center));
 
     if ui.selected_panel == Panel::Matches {
         matches_block = matches_block.border_style(highlight_style);
@@ -272,10 +581,99 @@ pub fn render_ui(app: &SharedState, ui: &mut UIState, frame: &mut Frame) {
         Paragraph::new(render_matches_text(
             app,
             ui,
-            &app.logbuf.tmp_read(),
+            &log_lines,
             matches_block.inner(sub_layout[1]),
         ))
         .block(matches_block),
         sub_layout[1],
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use crossbeam::channel;
+
+    use super::*;
+    use crate::logbuf::{Limits, LogBuf};
+    use crate::types::{Match, Source};
+
+    fn app() -> SharedState {
+        let (work_send, work_recv) = channel::unbounded();
+        let (match_send, match_recv) = channel::unbounded();
+        SharedState::new(
+            vec![Source::Stdin],
+            LogBuf::new(Limits::default()),
+            work_send,
+            work_recv,
+            match_send,
+            match_recv,
+        )
+    }
+
+    fn rows(text: &Text) -> Vec<String> {
+        text.lines
+            .iter()
+            .map(|line| line.spans.iter().map(|s| s.content.as_ref()).collect())
+            .map(|row: String| row.trim_end().to_string())
+            .collect()
+    }
+
+    // lines keep coming while a frame is drawn and they are searched right away
+    #[test]
+    fn matches_past_the_log_snapshot_are_not_shown() {
+        let app = app();
+        for i in 0..10 {
+            app.logbuf.write(0, &format!("hello {i}"));
+        }
+        let log_lines = app.logbuf.read();
+        for i in 10..20 {
+            app.logbuf.write(0, &format!("hello {i}"));
+        }
+        let matches = (0..20)
+            .map(|lineno| Match {
+                lineno,
+                source: 0,
+                version: 0,
+            })
+            .collect();
+        app.matches.lock().unwrap().insert(matches);
+
+        let rect = Rect::new(0, 0, 40, 4);
+        let mut ui = UIState::default();
+        let text = render_matches_text(&app, &mut ui, &log_lines, rect);
+        assert_eq!(rows(&text), ["hello 6", "hello 7", "hello 8", "hello 9"]);
+
+        ui.following = false;
+        ui.matches_selected = Some(19);
+        let text = render_matches_text(&app, &mut ui, &log_lines, rect);
+        let snapshot: Vec<_> = (0..10).map(|i| format!("hello {i}")).collect();
+        assert!(rows(&text).iter().all(|row| snapshot.contains(row)));
+    }
+
+    // a stream keeps adding lines while they are searched
+    #[test]
+    fn progress_ignores_lines_added_after_the_search_started() {
+        let app = app();
+        for i in 0..10 {
+            app.logbuf.write(0, &format!("hello {i}"));
+        }
+        app.coverage.lock().unwrap().reset(1, 10);
+        for i in 10..20 {
+            app.logbuf.write(0, &format!("hello {i}"));
+        }
+        let ui = UIState {
+            search_started: Some(Instant::now()),
+            ..UIState::default()
+        };
+
+        app.coverage.lock().unwrap().insert(1, 0..5);
+        let title = render_matches_title(&app, &ui, &app.logbuf.read());
+        assert!(title.contains("50%"), "{title}");
+
+        app.coverage.lock().unwrap().insert(1, 5..10);
+        let title = render_matches_title(&app, &ui, &app.logbuf.read());
+        assert_eq!(title, " Matches 0 ");
+    }
+}
@@ -0,0 +1,189 @@
+use std::ops::Range;
+
+use crate::logbuf::LogReader;
+use crate::matches::Matches;
+use crate::types::UIState;
+
+// Decides which lines of the log are shown in the log panel.
+// Positions are always line numbers of the log, hidden lines are skipped over.
+pub struct LogView<'a> {
+    lines: &'a LogReader<'a>,
+    hidden_sources: u64,
+    // when set only these lines are shown
+    only: Option<&'a Matches>,
+    // lines shown around each of only
+    context: usize,
+}
+
+// A row of the log panel
+#[derive(Clone, Copy)]
+pub enum Row {
+    Line(usize),
+    // between lines that are not next to each other in the filtered view
+    Separator,
+}
+
+impl<'a> LogView<'a> {
+    pub fn new(lines: &'a LogReader<'a>, hidden_sources: u64) -> Self {
+        LogView {
+            lines,
+            hidden_sources,
+            only: None,
+            context: 0,
+        }
+    }
+
+    // view showing only the matched lines and context lines around them
+    pub fn filtered(
+        lines: &'a LogReader<'a>,
+        hidden_sources: u64,
+        matches: &'a Matches,
+        context: usize,
+    ) -> Self {
+        LogView {
+            lines,
+            hidden_sources,
+            only: Some(matches),
+            context,
+        }
+    }
+
+    // View of the log panel, filtered only while searching.
+    // Matches have to stay locked while it is used.
+    pub fn of_panel(
+        lines: &'a LogReader<'a>,
+        ui: &UIState,
+        searching: bool,
+        matches: &'a Matches,
+    ) -> Self {
+        if ui.filtered_view && searching {
+            LogView::filtered(lines, ui.hidden_sources, matches, ui.context_lines)
+        } else {
+            LogView::new(lines, ui.hidden_sources)
+        }
+    }
+
+    // first line not evicted
+    pub fn first(&self) -> usize {
+        self.lines.first()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    // indexes of shown matches that are still in the log
+    fn only(&self) -> Option<(&'a Matches, Range<usize>)> {
+        let only = self.only?;
+        let start = only.partition_point(|m| m.lineno < self.first());
+        let end = only.partition_point(|m| m.lineno < self.len());
+        Some((only, start..end.max(start)))
+    }
+
+    // first visible line at or after lineno
+    pub fn next(&self, lineno: usize) -> Option<usize> {
+        let mut lineno = lineno.max(self.first());
+        loop {
+            if let Some((only, shown)) = self.only() {
+                // skip to the context of the next match
+                let i = only.partition_point(|m| m.lineno + self.context < lineno);
+                let next = only.get(i.max(shown.start)).filter(|_| i < shown.end)?;
+                lineno = lineno.max(next.lineno.saturating_sub(self.context));
+            }
+            // lines of hidden sources are skipped by whole runs
+            let visible = self.lines.next_visible(lineno, self.hidden_sources)?;
+            if visible == lineno {
+                return Some(lineno);
+            }
+            lineno = visible;
+        }
+    }
+
+    // last visible line at or before lineno
+    pub fn prev(&self, lineno: usize) -> Option<usize> {
+        let mut lineno = lineno.min(self.len().checked_sub(1)?);
+        loop {
+            if let Some((only, shown)) = self.only() {
+                // skip to the context of the previous match
+                let i = only.partition_point(|m| m.lineno.saturating_sub(self.context) <= lineno);
+                let i = i
+                    .min(shown.end)
+                    .checked_sub(1)
+                    .filter(|&i| i >= shown.start)?;
+                lineno = lineno.min(only.get(i)?.lineno + self.context);
+            }
+            let visible = self.lines.prev_visible(lineno, self.hidden_sources)?;
+            if visible == lineno {
+                return Some(lineno);
+            }
+            lineno = visible;
+        }
+    }
+
+    // grep like separators are shown between hunks with context
+    fn separated(&self, above: usize, below: usize) -> bool {
+        self.only.is_some() && self.context > 0 && above + 1 < below
+    }
+
+    // moves by count visible lines, stops at the first and last one
+    pub fn step(&self, from: usize, count: isize) -> usize {
+        if self.only.is_none() && self.hidden_sources == 0 {
+            return from
+                .saturating_add_signed(count)
+                .min(self.len().saturating_sub(1))
+                .max(self.first());
+        }
+
+        let Some(mut current) = self.next(from).or_else(|| self.prev(from)) else {
+            return from;
+        };
+        for _ in 0..count.unsigned_abs() {
+            let next = if count > 0 {
+                self.next(current + 1)
+            } else {
+                current.checked_sub(1).and_then(|i| self.prev(i))
+            };
+            match next {
+                Some(i) => current = i,
+                None => break,
+            }
+        }
+        current
+    }
+
+    // top line of a page that ends with the last visible line
+    pub fn last_page(&self, height: usize) -> usize {
+        let Some(mut top) = self.prev(self.len().saturating_sub(1)) else {
+            return 0;
+        };
+        let mut rows = 1;
+        while let Some(above) = top.checked_sub(1).and_then(|i| self.prev(i)) {
+            rows += 1 + self.separated(above, top) as usize;
+            if rows > height {
+                break;
+            }
+            top = above;
+        }
+        top
+    }
+
+    // rows of a page starting at top
+    pub fn window(&self, top: usize, height: usize) -> Vec<Row> {
+        let mut rows = Vec::with_capacity(height);
+        let mut last = None;
+        while rows.len() < height {
+            let Some(lineno) = self.next(last.map_or(top, |i| i + 1)) else {
+                break;
+            };
+            if last.is_some_and(|last| self.separated(last, lineno)) {
+                rows.push(Row::Separator);
+                if rows.len() == height {
+                    break;
+                }
+            }
+            rows.push(Row::Line(lineno));
+            last = Some(lineno);
+        }
+        rows
+    }
+}
@@ -2,41 +2,78 @@ use std::sync::{atomic::Ordering, Arc};
 
 use crossbeam::channel;
 
-use crate::types::{Match, SearchCriteria, SharedState};
+use crate::types::{Match, SearchCriteria, SharedState, WorkItem, CHUNK_BYTES};
 
-pub fn worker_thread(
-    app_handle: Arc<SharedState>,
-    channel: channel::Receiver<(usize, usize)>,
-) -> ! {
+pub fn worker_thread(app_handle: Arc<SharedState>, channel: channel::Receiver<WorkItem>) -> ! {
     let mut version = 0;
-    let mut regex = SearchCriteria { re: None };
+    let mut regex = SearchCriteria {
+        query: None,
+        filters: Vec::new(),
+        hidden_sources: 0,
+    };
     loop {
-        let range = channel.recv().unwrap();
+        let WorkItem {
+            lines,
+            version: item_version,
+            ..
+        } = channel.recv().unwrap();
 
-        let current = app_handle.search_version.load(Ordering::Relaxed);
-        if current != version {
-            // update version and regex
-            version = current;
-            regex = app_handle.search.read().unwrap().clone();
+        // work queued for a previous search is dropped without searching
+        if item_version != app_handle.search_version.load(Ordering::SeqCst) {
+            continue;
+        }
+        if item_version != version {
+            // version is read with the search locked, it is bumped only while writing it
+            let search = app_handle.search.read().unwrap();
+            version = app_handle.search_version.load(Ordering::SeqCst);
+            regex = search.clone();
+            if item_version != version {
+                continue;
+            }
         }
 
-        let mut matches = Vec::new();
+        if !regex.is_active() {
+            continue;
+        }
+
+        // the range is searched in chunks of about the same amount of text
+        let log_lines = app_handle.logbuf.read();
+        let mut start = lines.start;
+        while start < lines.end {
+            // the search was changed meanwhile
+            if app_handle.search_version.load(Ordering::SeqCst) != version {
+                break;
+            }
+            // lines might have been evicted since the range was sent
+            let from = start.max(log_lines.first()).min(lines.end);
+            let end = log_lines.chunk_end(from..lines.end, CHUNK_BYTES);
 
-        if let Some(re) = &regex.re {
-            let log_lines = app_handle.logbuf.tmp_read();
-            for i in range.0..range.1 {
-                if re.is_match(&log_lines[i]) {
+            let mut matches = Vec::new();
+            for i in from..end {
+                let source = log_lines.source(i);
+                if regex.is_source_hidden(source) {
+                    continue;
+                }
+                if regex.is_match(log_lines.line(i)) {
                     matches.push(Match {
                         lineno: i,
+                        source,
                         // TODO: still needed here ?
                         version,
                     });
                 }
             }
-        }
-
-        if !matches.is_empty() {
-            app_handle.matches_channel_send.send(matches).unwrap();
+            app_handle
+                .coverage
+                .lock()
+                .unwrap()
+                .insert(version, start..end);
+            // progress of the search is shown
+            app_handle.request_redraw();
+            if !matches.is_empty() {
+                app_handle.matches_channel_send.send(matches).unwrap();
+            }
+            start = end;
         }
     }
 }